@@ -30,9 +30,12 @@ return kelili.io_run_fun(function()
         if r_transaction.dest ~= parent_hash then
           return {error = "Transaction not for us!"}
         end
-        local a = state.balance + r_transaction.amount
-        print("CatCoin received ", state.balance .. " + " .. r_transaction.amount .. " = " .. state.balance + r_transaction.amount)
-        state.balance = state.balance + r_transaction.amount
+        local balance = state.balance:checked_add(r_transaction.amount)
+        if balance == nil then
+          return {error = "Balance overflow!"}
+        end
+        print("CatCoin received ", state.balance .. " + " .. r_transaction.amount .. " = " .. balance)
+        state.balance = balance
         transaction.amount = r_transaction.amount
         return {
           update = mk_update(state, this_hash),
@@ -1,45 +1,41 @@
 local crypto = require("crypto")
 local serpent = require("lua/serpent")
 
-local rng = crypto.Random.from_entropy()
+local rng
 
-function lua_to_scalar(obj)
-  return crypto.Scalar.from(crypto.U256.hash(require("lua/hash").hash(obj)))
+-- The bytes that get signed for a Lua value.
+function message_bytes(obj)
+  if type(obj) == "string" then
+    return obj
+  end
+  return crypto.encode(obj)
 end
 
 pk_metatable = {
   __index = {
-    as_scalar = function(self) return self.value end,
     verify = function(self, signature, message)
-      local new_challenge = lua_to_scalar({message, signature.response * crypto.Point.generator() + signature.challenge * self:as_scalar()})
-      return new_challenge == signature.challenge
+      return crypto.Signature.verify(self.key, message_bytes(message), signature)
     end,
   },
   __serpent = function(self, serialize)
-    return "require(\"lua/crypto_util\").set_pk_metatable({ value = " .. serialize(self.value) .. "})"
+    return "require(\"lua/crypto_util\").set_pk_metatable({ key = " .. serialize(self.key) .. "})"
   end
 }
 
 sk_metatable = {
   __index = {
-    as_scalar = function(self) return self.value end,
     sign = function(self, message)
-      local alpha = crypto.Scalar.random(rng)
-      local _ = alpha * crypto.Point.generator()
-      local challenge = lua_to_scalar({message, alpha * crypto.Point.generator()})
-      local response = alpha - challenge * self:as_scalar()
-      return {
-        challenge = challenge,
-        response = response,
-      }
+      return crypto.Signature.sign(self.key, message_bytes(message))
     end,
     pk = function(self)
-      return set_pk_metatable({value = self:as_scalar() * crypto.Point.generator()})
+      return set_pk_metatable({key = self.key:public_key()})
     end
   }}
 
   function gen_sk()
-    return set_sk_metatable({value = crypto.Scalar.random(rng)})
+    -- Only made when needed, since blocks that load this module can't use entropy.
+    rng = rng or crypto.Random.from_entropy()
+    return set_sk_metatable({key = crypto.SigningKey.random(rng)})
   end
 
   function set_sk_metatable(t)
@@ -53,6 +49,7 @@ sk_metatable = {
   end
   return {
     gen_sk = gen_sk,
+    message_bytes = message_bytes,
     set_pk_metatable = set_pk_metatable,
     set_sk_metatable = set_sk_metatable,
   }
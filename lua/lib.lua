@@ -1,3 +1,5 @@
+-- The node passes in a `coroutine.resume` that keeps errors as they are.
+local resume = ...
 crypto = require("crypto")
 serpent = require("lua/serpent")
 local function run_fun(f)
@@ -28,7 +30,7 @@ IO = {
   call_bubble = function(hash, max_mana, max_memo)
     local ret = IO.call(hash, max_mana, max_memo)
     if ret.error then
-      IO.done({error = {message = "Had an error while calling " .. hash:serialize(), data = ret}})
+      IO.done({error = {message = "Had an error while calling " .. tostring(hash), data = ret}})
     end
     return ret
   end,
@@ -42,11 +44,20 @@ IO = {
     local marker = IO.mark()
     local obj, hash = IO.open(marker(nil))
     return hash
+  end,
+  -- A random number generator seeded from the hash of the current block, which
+  -- gives the same numbers on every node. Each call starts the same stream over,
+  -- unless it's given a different label.
+  random = function(label)
+    return crypto.Random.for_block(IO.hash(), label)
   end
 }
 
 local function run_coro(co, ...)
-  local succ, t, action = coroutine.resume(co, ...)
+  local succ, t, action = resume(co, ...)
+  if not succ then
+    t, action = "error", {value = t}
+  end
   if action == nil then
     action = {}
   end
@@ -0,0 +1,305 @@
+//! Blocks and their canonical encoding.
+//!
+//! The hash of a block is the hash of its encoding, so every implementation has to
+//! encode a block to the exact same bytes. All integers are little-endian, and
+//! fields come in this order:
+//!
+//! ```text
+//! version       u8   always VERSION
+//! index         u64
+//! mana_limit    u64
+//! memo_limit    u64
+//! name          u8   0 for no name, 1 for a name, followed by:
+//!                 u32  length of the name
+//!                 ...  the name, in UTF-8
+//! code          u32  length of the code
+//!               ...  the code
+//! timestamp     u8   0 for no timestamp, 1 for a timestamp, followed by:
+//!                 u64  seconds since the Unix epoch
+//! dependencies  u32  amount of dependencies
+//!               ...  the hash of each, 32 bytes each
+//! author        u8   0 for no author, 1 for an author, followed by:
+//!                 32 bytes  Ed25519 public key
+//!                 64 bytes  Ed25519 signature of everything before it
+//! ```
+//!
+//! Each block has exactly one encoding. `Block::decode` rejects anything else,
+//! including unknown versions, flags other than 0 and 1, trailing bytes, and
+//! signatures that don't verify.
+use std::error::Error;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use crate::types::Id;
+
+pub type Code = Box<[u8]>;
+
+/// Version of the encoding that `Block::encode` produces.
+pub const VERSION: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    /// Distinguishes blocks that are otherwise the same, so that the same code can
+    /// be turned into blocks with different hashes, which have different marks and
+    /// cached results. It has no other meaning, and is 0 unless set explicitly.
+    pub index: u64,
+    /// Maximum amount of mana that running the block can use. 0 means no limit.
+    pub mana_limit: u64,
+    /// Maximum amount of memory in bytes that running the block can allocate. 0 means no limit.
+    pub memo_limit: u64,
+    /// Lua source or bytecode. It should return an IO action.
+    pub code: Code,
+    /// Only used when reporting on the block, like in error messages.
+    pub name: Option<String>,
+    /// When the block claims to have been made, in seconds since the Unix epoch.
+    /// Nothing checks it.
+    pub timestamp: Option<u64>,
+    /// Blocks that the code says it's going to `call`, so that they can be fetched early.
+    /// The code is free to call other blocks too.
+    pub dependencies: Vec<Id>,
+    pub author: Option<Author>,
+}
+
+/// Who made a block, and their signature of the rest of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Author {
+    pub public_key: VerifyingKey,
+    pub signature: Signature,
+}
+
+impl Block {
+    /// A block with no limits and no metadata.
+    pub fn new(code: Code, name: Option<String>) -> Self {
+        Block {
+            index: 0,
+            mana_limit: 0,
+            memo_limit: 0,
+            code,
+            name,
+            timestamp: None,
+            dependencies: Vec::new(),
+            author: None,
+        }
+    }
+    /// Sign the block with `key`, making its owner the author.
+    ///
+    /// This has to be done after setting every other field, since the signature covers them.
+    pub fn sign(&mut self, key: &SigningKey) {
+        let public_key = key.verifying_key();
+        let mut body = self.encode_body();
+        body.push(1);
+        body.extend_from_slice(public_key.as_bytes());
+        self.author = Some(Author {
+            public_key,
+            signature: key.sign(&body),
+        });
+    }
+    /// Everything up to the author.
+    fn encode_body(&self) -> Vec<u8> {
+        let mut out = vec![VERSION];
+        out.extend_from_slice(&self.index.to_le_bytes());
+        out.extend_from_slice(&self.mana_limit.to_le_bytes());
+        out.extend_from_slice(&self.memo_limit.to_le_bytes());
+        match &self.name {
+            Some(name) => {
+                out.push(1);
+                out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                out.extend_from_slice(name.as_bytes());
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&(self.code.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.code);
+        match self.timestamp {
+            Some(timestamp) => {
+                out.push(1);
+                out.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&(self.dependencies.len() as u32).to_le_bytes());
+        for dependency in &self.dependencies {
+            out.extend_from_slice(&dependency.to_le_bytes());
+        }
+        out
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = self.encode_body();
+        match &self.author {
+            Some(author) => {
+                out.push(1);
+                out.extend_from_slice(author.public_key.as_bytes());
+                out.extend_from_slice(&author.signature.to_bytes());
+            }
+            None => out.push(0),
+        }
+        out
+    }
+    pub fn decode(data: &[u8]) -> Result<Block, Box<dyn Error>> {
+        let mut reader = Reader(data);
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("Unknown block version {}", version).into());
+        }
+        let index = reader.u64()?;
+        let mana_limit = reader.u64()?;
+        let memo_limit = reader.u64()?;
+        let name = match reader.flag("name")? {
+            false => None,
+            true => {
+                let len = reader.u32()?;
+                Some(String::from_utf8(reader.bytes(len as usize)?.to_vec())?)
+            }
+        };
+        let len = reader.u32()?;
+        let code = reader.bytes(len as usize)?.into();
+        let timestamp = match reader.flag("timestamp")? {
+            false => None,
+            true => Some(reader.u64()?),
+        };
+        let amount = reader.u32()?;
+        let mut dependencies = Vec::new();
+        for _ in 0..amount {
+            dependencies.push(Id::from_le_bytes(reader.bytes(32)?.try_into()?));
+        }
+        let signed_len = data.len() - reader.0.len();
+        let author = match reader.flag("author")? {
+            false => None,
+            true => {
+                let public_key = VerifyingKey::from_bytes(reader.bytes(32)?.try_into()?)?;
+                let signed = &data[..signed_len + 1 + 32];
+                let signature = Signature::from_bytes(reader.bytes(64)?.try_into()?);
+                public_key
+                    .verify_strict(signed, &signature)
+                    .map_err(|_| "Invalid block signature")?;
+                Some(Author {
+                    public_key,
+                    signature,
+                })
+            }
+        };
+        if !reader.0.is_empty() {
+            return Err("Trailing bytes after block".into());
+        }
+        Ok(Block {
+            index,
+            mana_limit,
+            memo_limit,
+            code,
+            name,
+            timestamp,
+            dependencies,
+            author,
+        })
+    }
+}
+
+/// Reads the integers and fields of canonical encodings, like those of blocks and `Value`s.
+pub(crate) struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.0.len() < len {
+            return Err("Data ends too early".into());
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+    pub fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub fn flag(&mut self, field: &str) -> Result<bool, Box<dyn Error>> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(format!("Invalid {} flag {}", field, x).into()),
+        }
+    }
+    pub fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+    pub fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block() -> Block {
+        let mut block = Block::new(b"return 1".to_vec().into(), Some("test".to_string()));
+        block.index = 3;
+        block.mana_limit = 1000;
+        block
+    }
+
+    #[test]
+    fn round_trip() {
+        let block = block();
+        assert_eq!(Block::decode(&block.encode()).unwrap(), block);
+        let unnamed = Block::new(Box::default(), None);
+        assert_eq!(Block::decode(&unnamed.encode()).unwrap(), unnamed);
+    }
+
+    fn signed() -> Block {
+        let mut block = block();
+        block.timestamp = Some(1_700_000_000);
+        block.dependencies = vec![Id::ONE, Id::MAX];
+        block.sign(&SigningKey::from_bytes(&[7; 32]));
+        block
+    }
+
+    #[test]
+    fn signed_round_trip() {
+        let block = signed();
+        assert_eq!(Block::decode(&block.encode()).unwrap(), block);
+    }
+
+    #[test]
+    fn edited_signed_blocks() {
+        let encoding = signed().encode();
+        let mut body = encoding.clone();
+        // The last byte of the code.
+        let code_end = encoding.windows(8).position(|x| x == b"return 1").unwrap() + 7;
+        body[code_end] = b'2';
+        let mut signature = encoding.clone();
+        *signature.last_mut().unwrap() ^= 1;
+        for data in [body, signature] {
+            assert!(
+                Block::decode(&data).is_err(),
+                "decoded {}",
+                hex::encode(&data)
+            );
+        }
+    }
+
+    #[test]
+    fn non_canonical_encodings() {
+        let encoding = block().encode();
+        // The name flag comes after the version and three u64s.
+        let name_flag = 1 + 3 * 8;
+        let mut trailing = encoding.clone();
+        trailing.push(0);
+        let mut flag = encoding.clone();
+        flag[name_flag] = 2;
+        let mut version = encoding.clone();
+        version[0] = VERSION + 1;
+        let mut utf8 = encoding.clone();
+        utf8[name_flag + 1 + 4] = 0xff;
+        for data in [
+            trailing,
+            flag,
+            version,
+            utf8,
+            encoding[..encoding.len() - 1].to_vec(),
+        ] {
+            assert!(
+                Block::decode(&data).is_err(),
+                "decoded {}",
+                hex::encode(&data)
+            );
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Storing data of any size in the DHT as a Merkle DAG of small chunks.
+//!
+//! Data is split into chunks that fit in a `CHUNK_SIZE` packet. Each chunk starts
+//! with a tag byte: `LEAF` chunks hold a piece of the data, and `LINKS` chunks hold
+//! the hashes of the chunks below them, in order. The hash of the topmost chunk,
+//! the root, is the hash of the whole data. Data that fits in a single leaf is
+//! stored as just that leaf.
+use std::error::Error;
+
+use futures::future::join_all;
+
+use crate::{
+    dht::PeerHandle,
+    hash::{self, Algorithm},
+    types::Id,
+};
+
+/// Maximum size of a chunk, tag included.
+pub const CHUNK_SIZE: usize = 512;
+/// How many bytes of the data fit in a leaf.
+pub const LEAF_SIZE: usize = CHUNK_SIZE - 1;
+/// How many hashes fit in a links chunk.
+pub const LINKS_PER_CHUNK: usize = (CHUNK_SIZE - 1) / 32;
+/// Deepest tree that gets fetched. Enough for more data than anyone would store.
+pub const MAX_DEPTH: usize = 8;
+/// Most chunks that get fetched for one piece of data, which is about 32 MiB of leaves.
+/// Without it, a root could make `fetch` ask for `LINKS_PER_CHUNK.pow(MAX_DEPTH)` chunks.
+pub const MAX_CHUNKS: usize = 1 << 16;
+
+pub const LEAF: u8 = 0;
+pub const LINKS: u8 = 1;
+
+/// Split `data` into chunks hashed with `algorithm`. Returns the root hash and the chunks, with the root last.
+pub fn split(algorithm: Algorithm, data: &[u8]) -> (Id, Vec<Box<[u8]>>) {
+    let mut chunks = Vec::new();
+    let mut level: Vec<Id> = Vec::new();
+    for piece in data.chunks(LEAF_SIZE) {
+        level.push(push_chunk(&mut chunks, algorithm, LEAF, piece));
+    }
+    if level.is_empty() {
+        level.push(push_chunk(&mut chunks, algorithm, LEAF, &[]));
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(LINKS_PER_CHUNK)
+            .map(|links| {
+                let bytes: Vec<u8> = links.iter().flat_map(|x| x.to_le_bytes()).collect();
+                push_chunk(&mut chunks, algorithm, LINKS, &bytes)
+            })
+            .collect();
+    }
+    (level[0], chunks)
+}
+
+fn push_chunk(chunks: &mut Vec<Box<[u8]>>, algorithm: Algorithm, tag: u8, contents: &[u8]) -> Id {
+    let mut chunk = Vec::with_capacity(contents.len() + 1);
+    chunk.push(tag);
+    chunk.extend_from_slice(contents);
+    let hash = hash::hash_with(algorithm, &chunk);
+    chunks.push(chunk.into_boxed_slice());
+    hash
+}
+
+/// Store `data` as chunks hashed with `algorithm` in `dht`, and return its root hash.
+pub async fn store(
+    dht: &PeerHandle,
+    algorithm: Algorithm,
+    data: &[u8],
+) -> Result<Id, Box<dyn Error>> {
+    let (root, chunks) = split(algorithm, data);
+    let stores = chunks
+        .into_iter()
+        .map(|chunk| dht.store_with(algorithm, chunk));
+    for hash in join_all(stores).await {
+        hash?;
+    }
+    Ok(root)
+}
+
+/// Fetch the data with root hash `root` from `dht`, checking every chunk against its hash.
+///
+/// Returns `None` if any of the chunks can't be found, and fails if there are more than `MAX_CHUNKS`.
+pub async fn fetch(dht: &PeerHandle, root: &Id) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut level = vec![*root];
+    let mut fetched = 0;
+    for _ in 0..=MAX_DEPTH {
+        fetched += level.len();
+        if fetched > MAX_CHUNKS {
+            return Err("Data has too many chunks".into());
+        }
+        let mut chunks = Vec::new();
+        for chunk in join_all(level.iter().map(|hash| dht.find(hash))).await {
+            match chunk? {
+                Some(chunk) => chunks.push(chunk),
+                None => return Ok(None),
+            }
+        }
+        for (hash, chunk) in level.iter().zip(&chunks) {
+            if !hash::verify(hash, chunk) {
+                return Err("Chunk doesn't match its hash".into());
+            }
+        }
+        match chunks[0].first() {
+            Some(&LEAF) => {
+                let mut data = Vec::new();
+                for chunk in chunks {
+                    if chunk.first() != Some(&LEAF) {
+                        return Err("Chunks at the same depth have different tags".into());
+                    }
+                    data.extend_from_slice(&chunk[1..]);
+                }
+                return Ok(Some(data));
+            }
+            Some(&LINKS) => {
+                let mut next = Vec::new();
+                for chunk in chunks {
+                    let links = &chunk[1..];
+                    if chunk[0] != LINKS || links.is_empty() || links.len() % 32 != 0 {
+                        return Err("Invalid links chunk".into());
+                    }
+                    for link in links.chunks(32) {
+                        next.push(Id::from_le_bytes(link.try_into()?));
+                    }
+                }
+                level = next;
+            }
+            _ => return Err("Invalid chunk tag".into()),
+        }
+    }
+    Err("Chunk tree is too deep".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::Peer;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dht = Peer::new(&mut rand::thread_rng()).spawn();
+        let data: Vec<u8> = (0..20_000).map(|x| x as u8).collect();
+        let root = store(&dht, hash::DEFAULT, &data).await.unwrap();
+        assert_eq!(fetch(&dht, &root).await.unwrap(), Some(data));
+    }
+
+    #[tokio::test]
+    async fn too_many_chunks() {
+        // A few chunks that link to the same chunk over and over expand to far more than `MAX_CHUNKS`.
+        let dht = Peer::new(&mut rand::thread_rng()).spawn();
+        let mut hash = dht.store(Box::new([LEAF])).await.unwrap();
+        for _ in 0..6 {
+            let mut chunk = vec![LINKS];
+            for _ in 0..LINKS_PER_CHUNK {
+                chunk.extend_from_slice(&hash.to_le_bytes());
+            }
+            hash = dht.store(chunk.into_boxed_slice()).await.unwrap();
+        }
+        let error = fetch(&dht, &hash).await.unwrap_err();
+        assert_eq!(error.to_string(), "Data has too many chunks");
+    }
+}
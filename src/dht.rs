@@ -1,18 +1,35 @@
 /// Lazy implementation of the Kademlia protocol.
 use rand::prelude::*;
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error,
     time::Duration,
 };
-use tokio::sync::mpsc::{self, channel};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+
+use tracing::{debug_span, trace, Instrument};
 
-use blake2::Digest;
+use crate::{
+    hash::{self, Algorithm},
+    metrics,
+};
 
 pub type Hashed = [u8; 64];
 
 pub use super::types::Id;
 
+/// Buckets that haven't seen a lookup in this long get refreshed by `run`.
+pub const BUCKET_REFRESH_AGE: Duration = Duration::from_secs(60 * 60);
+/// How often `run` checks for stale buckets.
+pub const BUCKET_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// Finds that haven't been answered in this long are given up on.
+pub const FIND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Size of the message queue of each peer. Messages sent to a full queue are dropped.
+pub const QUEUE_SIZE: usize = 1024;
+
 #[derive(Clone, Debug)]
 pub enum MessageData {
     Ping {
@@ -31,12 +48,67 @@ pub enum MessageData {
         id: u64,
         peers: Vec<PeerInfo>,
     },
+    /// `propagate` is set when the data should be stored, under its hash with
+    /// that algorithm, and passed on towards the peers closest to it.
     FoundData {
         id: u64,
         data: Box<[u8]>,
-        propagate: bool,
+        propagate: Option<Algorithm>,
+    },
+    /// `provider` says that `value` is what it has for `key`.
+    /// Passed on towards the peers closest to `key`, like `FoundData` with `propagate`.
+    Announce {
+        key: Id,
+        provider: Id,
+        value: Box<[u8]>,
+    },
+    FindRecords {
+        id: u64,
+        key: Id,
+    },
+    FoundRecords {
+        id: u64,
+        records: Vec<(Id, Box<[u8]>)>,
+    },
+    /// Ask an executing node to run a block.
+    ExecuteRequest {
+        id: u64,
+        hash: Id,
+        mana_limit: u64,
+        memo_limit: u64,
+    },
+    /// The serialized result of running a block, or why it couldn't be run.
+    ExecuteResult {
+        id: u64,
+        result: Result<Box<[u8]>, String>,
+    },
+    /// Ask a reachable peer to pass on the messages that are sent to us through it.
+    RelayRequest,
+    /// A message for `to`, sent through the peer that relays for it.
+    RelayedMessage {
+        to: Id,
+        message: Box<Message>,
     },
-    Stop,
+}
+
+impl MessageData {
+    /// Name of the kind of message, for logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MessageData::Ping { .. } => "ping",
+            MessageData::Pong { .. } => "pong",
+            MessageData::Find { .. } => "find",
+            MessageData::FoundPeers { .. } => "found_peers",
+            MessageData::FoundData { .. } => "found_data",
+            MessageData::Announce { .. } => "announce",
+            MessageData::FindRecords { .. } => "find_records",
+            MessageData::FoundRecords { .. } => "found_records",
+            MessageData::ExecuteRequest { .. } => "execute_request",
+            MessageData::ExecuteResult { .. } => "execute_result",
+            MessageData::RelayRequest => "relay_request",
+            MessageData::RelayedMessage { .. } => "relayed_message",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -45,10 +117,21 @@ pub struct Message {
     contents: MessageData,
 }
 
+impl Message {
+    pub fn from(&self) -> &PeerInfo {
+        &self.from
+    }
+    pub fn contents(&self) -> &MessageData {
+        &self.contents
+    }
+}
+
 #[derive(Clone)]
 pub struct PeerInfo {
     tx: mpsc::Sender<Message>,
     id: Id,
+    /// The peer that messages for this one have to go through, if it can't be reached directly.
+    relay: Option<Box<PeerInfo>>,
 }
 use core::fmt::Debug;
 impl Debug for PeerInfo {
@@ -58,49 +141,325 @@ impl Debug for PeerInfo {
 }
 
 impl PeerInfo {
-    pub async fn send_peer_info(
+    pub fn id(&self) -> Id {
+        self.id
+    }
+    /// Queue a message for this peer.
+    ///
+    /// Like a datagram, the message is dropped if the peer can't take it right now,
+    /// so that two busy peers sending to each other can't deadlock.
+    ///
+    /// Messages for peers behind a relay are sent to the relay.
+    pub fn send(&self, msg: Message) -> Result<(), Box<dyn Error>> {
+        match &self.relay {
+            // Relays are reachable, so the message is never wrapped more than once.
+            Some(relay) => relay.direct().send(Message {
+                from: msg.from.clone(),
+                contents: MessageData::RelayedMessage {
+                    to: self.id,
+                    message: Box::new(msg),
+                },
+            }),
+            None => Ok(self.tx.try_send(msg)?),
+        }
+    }
+    /// This peer, reached directly instead of through its relay.
+    fn direct(&self) -> PeerInfo {
+        PeerInfo {
+            relay: None,
+            ..self.clone()
+        }
+    }
+    pub fn send_peer_info(&self, from: &PeerInfo, other: &PeerInfo) -> Result<(), Box<dyn Error>> {
+        self.send(Message {
+            from: from.clone(),
+            contents: MessageData::FoundPeers {
+                id: 0,
+                peers: vec![other.clone()],
+            },
+        })
+    }
+    pub fn send_find(&self, from: &PeerInfo, hash: &Id) -> Result<(), Box<dyn Error>> {
+        self.send(Message {
+            from: from.clone(),
+            contents: MessageData::Find { id: 0, hash: *hash },
+        })
+    }
+}
+
+/// Where the data found by a find gets sent, along with its hash, or for
+/// records, along with the id of their provider. The find is over once all clones
+/// of it have been dropped, which happens when every branch of the lookup
+/// has either run out of peers to ask, run out of TTL, or timed out.
+pub type FindSender = mpsc::UnboundedSender<(Id, Box<[u8]>)>;
+
+/// Maximum amount of providers whose records are kept for each key.
+pub const MAX_PROVIDERS: usize = 20;
+/// Maximum amount of peers that a peer relays messages for.
+pub const MAX_RELAYED: usize = 64;
+/// Execute requests that haven't been answered in this long are given up on.
+pub const EXECUTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type ExecuteResult = Result<Box<[u8]>, String>;
+
+/// A block that a remote peer wants us to run, handed to whatever executes blocks for this peer.
+#[derive(Debug)]
+pub struct ExecuteJob {
+    pub hash: Id,
+    pub mana_limit: u64,
+    pub memo_limit: u64,
+    pub reply: oneshot::Sender<ExecuteResult>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FindKind {
+    /// Content-addressed data, stored with `store`.
+    Data,
+    /// Records announced by providers, stored with `announce`.
+    Records,
+}
+
+#[derive(Clone, Debug)]
+pub struct WaitingFind {
+    hash: Id,
+    kind: FindKind,
+    /// `None` for lookups that are only done to fill the buckets.
+    send_to: Option<FindSender>,
+    /// How many more times the lookup can move on to closer peers.
+    ttl: u8,
+    started_at: Instant,
+    /// Peers which have already been asked, so that they aren't asked again.
+    queried: HashSet<Id>,
+    /// Amount of asked peers which haven't answered yet.
+    pending: usize,
+}
+
+/// Requests from a `PeerHandle` to the task running its `Peer`.
+#[derive(Debug)]
+pub enum Command {
+    Find {
+        hash: Id,
+        send_to: FindSender,
+    },
+    Store {
+        algorithm: Algorithm,
+        data: Box<[u8]>,
+        reply: oneshot::Sender<Id>,
+    },
+    Announce {
+        key: Id,
+        value: Box<[u8]>,
+    },
+    FindRecords {
+        key: Id,
+        send_to: FindSender,
+    },
+    AddPeer {
+        info: PeerInfo,
+    },
+    ClosestPeers {
+        hash: Id,
+        amount: u32,
+        reply: oneshot::Sender<Vec<PeerInfo>>,
+    },
+    Execute {
+        peer: PeerInfo,
+        hash: Id,
+        mana_limit: u64,
+        memo_limit: u64,
+        reply: oneshot::Sender<ExecuteResult>,
+    },
+    SetExecutor {
+        jobs: mpsc::UnboundedSender<ExecuteJob>,
+    },
+    SetRelay {
+        relay: PeerInfo,
+    },
+    Bootstrap {
+        seeds: Vec<PeerInfo>,
+        send_to: FindSender,
+    },
+    RefreshBuckets {
+        max_age: Duration,
+    },
+}
+
+/// Cheaply clonable handle to a `Peer` running in its own task.
+///
+/// The task lives for as long as there are handles to it, serving remote
+/// requests in the background, and any number of finds can be in flight at once.
+#[derive(Clone, Debug)]
+pub struct PeerHandle {
+    commands: mpsc::Sender<Command>,
+    info: PeerInfo,
+}
+
+impl PeerHandle {
+    pub fn info(&self) -> PeerInfo {
+        self.info.clone()
+    }
+    pub fn id(&self) -> Id {
+        self.info.id
+    }
+    async fn command(&self, command: Command) -> Result<(), Box<dyn Error>> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| "Peer is not running".into())
+    }
+    pub async fn find(&self, hash: &Id) -> Result<Option<Box<[u8]>>, Box<dyn Error>> {
+        let started_at = Instant::now();
+        let (send_to, mut rx) = mpsc::unbounded_channel();
+        self.command(Command::Find {
+            hash: *hash,
+            send_to,
+        })
+        .await?;
+        let data = rx.recv().await.map(|x| x.1);
+        metrics::observe("dht.find_ms", started_at.elapsed().as_millis() as u64);
+        match data {
+            Some(_) => metrics::count("dht.finds.found", 1),
+            None => metrics::count("dht.finds.not_found", 1),
+        }
+        Ok(data)
+    }
+    /// Store `data` and return its hash.
+    pub async fn store(&self, data: Box<[u8]>) -> Result<Id, Box<dyn Error>> {
+        self.store_with(hash::DEFAULT, data).await
+    }
+    /// Store `data` under its hash with `algorithm`, and return that hash.
+    pub async fn store_with(
+        &self,
+        algorithm: Algorithm,
+        data: Box<[u8]>,
+    ) -> Result<Id, Box<dyn Error>> {
+        let (reply, rx) = oneshot::channel();
+        self.command(Command::Store {
+            algorithm,
+            data,
+            reply,
+        })
+        .await?;
+        Ok(rx.await?)
+    }
+    /// Tell the peers close to `key` that we have `value` for it.
+    pub async fn announce(&self, key: &Id, value: Box<[u8]>) -> Result<(), Box<dyn Error>> {
+        self.command(Command::Announce { key: *key, value }).await
+    }
+    /// Find the records announced for `key`, and who announced them.
+    pub async fn find_records(&self, key: &Id) -> Result<Vec<(Id, Box<[u8]>)>, Box<dyn Error>> {
+        let started_at = Instant::now();
+        let (send_to, mut rx) = mpsc::unbounded_channel();
+        self.command(Command::FindRecords { key: *key, send_to })
+            .await?;
+        let mut records = Vec::new();
+        while let Some(record) = rx.recv().await {
+            records.push(record);
+        }
+        metrics::observe(
+            "dht.find_records_ms",
+            started_at.elapsed().as_millis() as u64,
+        );
+        Ok(records)
+    }
+    pub async fn add_peer(&self, info: &PeerInfo) -> Result<(), Box<dyn Error>> {
+        self.command(Command::AddPeer { info: info.clone() }).await
+    }
+    /// The `amount` peers closest to `hash` that we know of, not counting ourselves.
+    pub async fn closest_peers(
         &self,
-        from: &PeerInfo,
-        other: &PeerInfo,
+        hash: &Id,
+        amount: u32,
+    ) -> Result<Vec<PeerInfo>, Box<dyn Error>> {
+        let (reply, rx) = oneshot::channel();
+        self.command(Command::ClosestPeers {
+            hash: *hash,
+            amount,
+            reply,
+        })
+        .await?;
+        Ok(rx.await?)
+    }
+    /// Ask `peer` to run the block `hash` and send back its serialized result.
+    pub async fn execute(
+        &self,
+        peer: &PeerInfo,
+        hash: &Id,
+        mana_limit: u64,
+        memo_limit: u64,
+    ) -> Result<ExecuteResult, Box<dyn Error>> {
+        let (reply, rx) = oneshot::channel();
+        self.command(Command::Execute {
+            peer: peer.clone(),
+            hash: *hash,
+            mana_limit,
+            memo_limit,
+            reply,
+        })
+        .await?;
+        rx.await.map_err(|_| "Execute request timed out".into())
+    }
+    /// Make `ExecuteRequest`s from other peers get sent to `jobs`.
+    pub async fn set_executor(
+        &self,
+        jobs: mpsc::UnboundedSender<ExecuteJob>,
     ) -> Result<(), Box<dyn Error>> {
-        Ok(self
-            .tx
-            .send(Message {
-                from: from.clone(),
-                contents: MessageData::FoundPeers {
-                    id: 0,
-                    peers: vec![other.clone()],
-                },
-            })
-            .await?)
-    }
-    pub async fn send_find(&self, from: &PeerInfo, hash: &Id) -> Result<(), Box<dyn Error>> {
-        Ok(self
-            .tx
-            .send(Message {
-                from: from.clone(),
-                contents: MessageData::Find {
-                    id: 0,
-                    hash: hash.clone(),
-                },
-            })
-            .await?)
+        self.command(Command::SetExecutor { jobs }).await
+    }
+    /// Have other peers reach this one through `relay`, for when it can't accept
+    /// inbound traffic. This should be done before bootstrapping, since the peers
+    /// that learn about us before that will try to reach us directly.
+    pub async fn set_relay(&mut self, relay: &PeerInfo) -> Result<(), Box<dyn Error>> {
+        self.command(Command::SetRelay {
+            relay: relay.clone(),
+        })
+        .await?;
+        self.info.relay = Some(Box::new(relay.direct()));
+        Ok(())
+    }
+    /// Join the network that `seeds` are part of, see `Peer::bootstrap`.
+    pub async fn bootstrap(&self, seeds: &[PeerInfo]) -> Result<(), Box<dyn Error>> {
+        let (send_to, mut rx) = mpsc::unbounded_channel();
+        self.command(Command::Bootstrap {
+            seeds: seeds.to_vec(),
+            send_to,
+        })
+        .await?;
+        // Wait for the lookup of our own id to be over.
+        while rx.recv().await.is_some() {}
+        self.command(Command::RefreshBuckets {
+            max_age: Duration::ZERO,
+        })
+        .await
     }
 }
 
 #[derive(Debug)]
 pub struct Peer {
-    store: HashMap<Id, Box<[u8]>>,
+    // Ordered, so that replicating to new peers happens in the same order every time.
+    store: BTreeMap<Id, Box<[u8]>>,
+    /// Announced records, by key and then by provider.
+    records: BTreeMap<Id, BTreeMap<Id, Box<[u8]>>>,
     k: u32,
     buckets: [Vec<PeerInfo>; 256],
+    /// Last time a lookup was started for an id that falls in each bucket.
+    bucket_lookups: [Option<Instant>; 256],
     peer_distance: HashMap<Id, i64>,
     msg_sent_at: HashMap<u64, u64>,
-    waiting_finds: HashMap<u64, (Id, mpsc::Sender<Option<(Id, Box<[u8]>)>>, u8)>,
+    waiting_finds: HashMap<u64, WaitingFind>,
     m_id_to_find_id: HashMap<u64, u64>,
+    waiting_executions: HashMap<u64, (Instant, oneshot::Sender<ExecuteResult>)>,
+    /// Where `ExecuteRequest`s get sent. Peers without one don't run blocks for others.
+    executor: Option<mpsc::UnboundedSender<ExecuteJob>>,
+    /// The peer that others reach us through, if any.
+    relay: Option<PeerInfo>,
+    /// The peers that we relay messages for, reached directly.
+    relayed: HashMap<Id, PeerInfo>,
     rx: mpsc::Receiver<Message>,
     tx: mpsc::Sender<Message>,
     id: Id,
     rng: Box<dyn N>,
+    started_at: Instant,
 }
 trait N: rand::RngCore + rand::CryptoRng + core::fmt::Debug + Send {}
 impl<T> N for T where T: rand::RngCore + rand::CryptoRng + core::fmt::Debug + Send {}
@@ -114,26 +473,21 @@ pub fn xor_distance(from: &Id, other: &Id) -> Id {
 }
 
 impl Peer {
-    pub fn hash(&self, data: &[u8]) -> Id {
-        let mut hasher = blake2::Blake2s256::new();
-        hasher.update(data);
-        ethnum::U256::from_le_bytes(hasher.finalize().into())
-    }
     pub fn distance_to(&self, other: &Id) -> Id {
         xor_distance(&self.id, other)
     }
-    pub fn bucket_num(&mut self, id: &Id) -> Option<usize> {
-        if id.leading_zeros() > 0 {
-            Some(256 - id.leading_zeros() as usize)
+    /// Index of the bucket for peers at distance `id`, which is the position of its highest bit.
+    pub fn bucket_num(&self, id: &Id) -> Option<usize> {
+        if *id != 0 {
+            Some(255 - id.leading_zeros() as usize)
         } else {
             None
         }
     }
-    pub fn find_closest_peers(&mut self, hash: &Id, amount: &u32) -> Vec<PeerInfo> {
+    pub fn find_closest_peers(&self, hash: &Id, amount: &u32) -> Vec<PeerInfo> {
         self.buckets
             .iter()
-            .map(|x| x.iter())
-            .flatten()
+            .flat_map(|x| x.iter())
             .fold(
                 BTreeMap::from([(xor_distance(&self.id, hash), self.info())]),
                 |mut acc, i| {
@@ -148,62 +502,117 @@ impl Peer {
             .cloned()
             .collect()
     }
-    pub async fn retry_find(&mut self, find_id: u64) -> Result<(), Box<dyn Error>> {
-        let (hash, send_to, _ttl) = self.waiting_finds.get(&find_id).unwrap().clone();
-        let peers = self.find_closest_peers(&hash, &3);
-        if peers.len() == 1 {
-            send_to.send(None).await?;
+    /// Ask the three closest peers to the hash which haven't been asked yet.
+    pub fn retry_find(&mut self, find_id: u64) -> Result<(), Box<dyn Error>> {
+        let find = self.waiting_finds.get(&find_id).unwrap();
+        let hash = find.hash;
+        let peers: Vec<_> = self
+            .find_closest_peers(&hash, &self.k.clone())
+            .into_iter()
+            .filter(|x| x.id != self.id && !find.queried.contains(&x.id))
+            .take(3)
+            .collect();
+        let find = self.waiting_finds.get_mut(&find_id).unwrap();
+        if peers.is_empty() && find.pending == 0 {
+            // Nobody else to ask.
+            self.waiting_finds.remove(&find_id);
             return Ok(());
         }
+        find.pending += peers.len();
+        find.queried.extend(peers.iter().map(|x| x.id));
+        let kind = find.kind;
         for peer in peers {
-            if peer.id != self.id {
-                let m_id = self.rng.next_u64();
-                self.m_id_to_find_id.insert(m_id, find_id);
-                peer.tx
-                    .send(Message {
-                        from: self.info(),
-                        contents: MessageData::Find {
-                            id: m_id,
-                            hash: hash.clone(),
-                        },
-                    })
-                    .await?;
-            }
+            let m_id = self.rng.next_u64();
+            self.m_id_to_find_id.insert(m_id, find_id);
+            let contents = match kind {
+                FindKind::Data => MessageData::Find { id: m_id, hash },
+                FindKind::Records => MessageData::FindRecords {
+                    id: m_id,
+                    key: hash,
+                },
+            };
+            // If this fails, the find will time out.
+            let _ = peer.send(Message {
+                from: self.info(),
+                contents,
+            });
         }
         Ok(())
     }
-    pub async fn find_with_ttl(
+    pub fn find_with_ttl(
         &mut self,
         hash: &Id,
-        send_to: &mpsc::Sender<Option<(Id, Box<[u8]>)>>,
+        kind: FindKind,
+        send_to: &Option<FindSender>,
         ttl: &u8,
     ) -> Result<(), Box<dyn Error>> {
-        if let Some(data) = self.store.get(hash) {
-            send_to.send(Some((hash.clone(), data.clone()))).await?;
-            return Ok(());
+        match kind {
+            FindKind::Data => {
+                if let Some(data) = self.store.get(hash) {
+                    reply(send_to, (*hash, data.clone()));
+                    return Ok(());
+                }
+            }
+            FindKind::Records => {
+                if let Some(records) = self.records.get(hash) {
+                    for (provider, value) in records {
+                        reply(send_to, (*provider, value.clone()));
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(idx) = self.bucket_num(&self.distance_to(hash)) {
+            self.bucket_lookups[idx] = Some(Instant::now());
         }
         let f_id = self.rng.next_u64();
+        self.waiting_finds.insert(
+            f_id,
+            WaitingFind {
+                hash: *hash,
+                kind,
+                send_to: send_to.clone(),
+                ttl: *ttl,
+                started_at: Instant::now(),
+                queried: HashSet::new(),
+                pending: 0,
+            },
+        );
+        self.retry_find(f_id)
+    }
+    /// Start a lookup whose only purpose is to learn about the peers close to `hash`.
+    pub fn lookup(&mut self, hash: &Id) -> Result<(), Box<dyn Error>> {
+        self.find_with_ttl(hash, FindKind::Data, &None, &5)
+    }
+    /// Start looking for the data with hash `hash`. It will be sent to `send_to` if found.
+    pub fn find(&mut self, hash: &Id, send_to: FindSender) -> Result<(), Box<dyn Error>> {
+        // When standalone, this just drops `send_to` if we don't have the data ourselves.
+        self.find_with_ttl(hash, FindKind::Data, &Some(send_to), &5)
+    }
+    /// Give up on finds that have been waiting for longer than `FIND_TIMEOUT`,
+    /// and on execute requests waiting for longer than `EXECUTE_TIMEOUT`.
+    pub fn expire_finds(&mut self) {
         self.waiting_finds
-            .insert(f_id, (hash.clone(), send_to.clone(), *ttl));
-        self.retry_find(f_id).await
-    }
-    pub async fn find(&mut self, hash: &Id) -> Result<Option<Box<[u8]>>, Box<dyn Error>> {
-        let (tx, mut rx) = channel(100);
-        self.find_with_ttl(hash, &tx, &5).await?;
-        let self_tx = self.tx.clone();
-        let stop_msg = self.make_msg(MessageData::Stop);
-        // TODO: Blocks. Better way?
-        let (tx2, mut rx2) = channel(100);
-        tokio::spawn(async move {
-            let m = rx.recv().await;
-            let _ = self_tx.send(stop_msg).await;
-            tx2.send(m).await.unwrap();
-        });
-        self.run().await.unwrap();
-        let v = rx2.recv().await.unwrap().unwrap();
-        Ok(v.map(|x| x.1))
+            .retain(|_, find| find.started_at.elapsed() < FIND_TIMEOUT);
+        self.waiting_executions
+            .retain(|_, (started_at, _)| started_at.elapsed() < EXECUTE_TIMEOUT);
+        let waiting_finds = &self.waiting_finds;
+        self.m_id_to_find_id
+            .retain(|_, find_id| waiting_finds.contains_key(find_id));
+    }
+    /// Whether this peer doesn't know about any other peers.
+    ///
+    /// A standalone peer keeps everything it stores and answers finds from its own
+    /// store only, without waiting on anyone. Once it learns about other peers it hands them the data they're
+    /// closer to, so it starts replicating without anything else having to change.
+    pub fn is_standalone(&self) -> bool {
+        self.buckets.iter().all(|x| x.is_empty())
     }
-    pub fn add_peer(&mut self, info: &PeerInfo) {
+    /// Add a peer to its bucket. Returns whether the peer wasn't known before.
+    pub fn add_peer(&mut self, info: &PeerInfo) -> bool {
+        if info.id == self.id {
+            return false;
+        }
         let dist = self.distance_to(&info.id);
         if let Some(idx) = self.bucket_num(&dist) {
             let bucket = &mut self.buckets[idx];
@@ -218,81 +627,148 @@ impl Peer {
                         .unwrap();
                     bucket.remove(max_item.0);
                 }
+                return bucket.iter().any(|x| x.id == info.id);
             }
         }
+        false
     }
-    pub fn make_msg(&mut self, msg: MessageData) -> Message {
+    /// Add a peer, and if it's new, send it the stored data that it's closer to than we are.
+    pub fn learn_peer(&mut self, info: &PeerInfo) -> Result<(), Box<dyn Error>> {
+        if self.add_peer(info) {
+            let closer: Vec<_> = self
+                .store
+                .iter()
+                .filter(|(h, _)| xor_distance(&info.id, h) < self.distance_to(h))
+                .filter_map(|(h, data)| Some((hash::algorithm_of(h)?, data.clone())))
+                .collect();
+            for (algorithm, data) in closer {
+                self.send_data(info, algorithm, data)?;
+            }
+            for (key, records) in &self.records {
+                if xor_distance(&info.id, key) < self.distance_to(key) {
+                    for (provider, value) in records {
+                        info.send(self.make_msg(MessageData::Announce {
+                            key: *key,
+                            provider: *provider,
+                            value: value.clone(),
+                        }))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn ping(&mut self, peer: &PeerInfo) -> Result<(), Box<dyn Error>> {
+        let id = self.rng.next_u64();
+        let time = self.now();
+        self.msg_sent_at.insert(id, time);
+        peer.send(self.make_msg(MessageData::Ping { id, time }))
+    }
+    /// Join the network that `seeds` are part of.
+    ///
+    /// The seeds are pinged and a lookup for our own id is started, which
+    /// makes the peers close to us learn about us and fills our buckets
+    /// with them. `send_to` is dropped once the lookup is over, and then the
+    /// rest of the buckets should be refreshed.
+    pub fn bootstrap(
+        &mut self,
+        seeds: &[PeerInfo],
+        send_to: Option<FindSender>,
+    ) -> Result<(), Box<dyn Error>> {
+        for seed in seeds {
+            if seed.id != self.id {
+                self.learn_peer(seed)?;
+                self.ping(seed)?;
+            }
+        }
+        let id = self.id;
+        self.find_with_ttl(&id, FindKind::Data, &send_to, &5)
+    }
+    /// Do a lookup for a random id in every bucket which hasn't
+    /// had one in `max_age`. Empty buckets closer than our closest peer are
+    /// skipped, since there is nobody to find there.
+    pub fn refresh_buckets(&mut self, max_age: Duration) -> Result<(), Box<dyn Error>> {
+        let Some(closest) = self.buckets.iter().position(|x| !x.is_empty()) else {
+            return Ok(());
+        };
+        for idx in closest..self.buckets.len() {
+            if self.bucket_lookups[idx].is_some_and(|x| x.elapsed() < max_age) {
+                continue;
+            }
+            let distance = self.random_distance_in_bucket(idx);
+            let target = self.distance_to(&distance);
+            self.lookup(&target)?;
+        }
+        Ok(())
+    }
+    fn random_distance_in_bucket(&mut self, idx: usize) -> Id {
+        let mut bytes = [0; 32];
+        self.rng.fill_bytes(&mut bytes);
+        let top_bit = Id::ONE << idx as u32;
+        (Id::from_le_bytes(bytes) & (top_bit - 1)) | top_bit
+    }
+    /// Milliseconds since this peer was created.
+    fn now(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64
+    }
+    pub fn make_msg(&self, msg: MessageData) -> Message {
         Message {
             from: self.info(),
             contents: msg,
         }
     }
-    pub async fn handle_msg(&mut self, msg: Message) -> Result<(), Box<dyn Error>> {
-        // println!("{} <- {} {:?}", encode_id(&self.id), encode_id(&msg.from.id), msg.contents);
-        self.add_peer(&msg.from);
+    pub fn handle_msg(&mut self, msg: Message) -> Result<(), Box<dyn Error>> {
+        trace!(from = %encode_id(&msg.from.id), contents = ?msg.contents, "Received");
+        metrics::count(&format!("dht.messages.{}", msg.contents.kind()), 1);
+        self.learn_peer(&msg.from)?;
         match msg.contents {
-            MessageData::Ping { id, time: _time } => {
-                msg.from
-                    .tx
-                    .send(Message {
-                        from: self.info(),
-                        contents: MessageData::Pong { id, time: 0 },
-                    })
-                    .await?;
+            MessageData::Ping { id, time } => {
+                msg.from.send(Message {
+                    from: self.info(),
+                    contents: MessageData::Pong { id, time },
+                })?;
             }
-            MessageData::Pong { id, time } => {
+            MessageData::Pong { id, time: _time } => {
                 if let Some(send_time) = self.msg_sent_at.remove(&id) {
                     self.peer_distance
-                        .insert(msg.from.id, (time as i64) - (send_time as i64));
+                        .insert(msg.from.id, (self.now() as i64) - (send_time as i64));
                 }
             }
             MessageData::Find { id, hash } => {
                 if let Some(data) = self.store.get(&hash) {
-                    msg.from
-                        .tx
-                        .send(Message {
-                            from: self.info(),
-                            contents: MessageData::FoundData {
-                                id,
-                                data: data.clone(),
-                                propagate: false,
-                            },
-                        })
-                        .await?;
+                    msg.from.send(Message {
+                        from: self.info(),
+                        contents: MessageData::FoundData {
+                            id,
+                            data: data.clone(),
+                            propagate: None,
+                        },
+                    })?;
                 } else {
                     // Return closest peers
-                    msg.from
-                        .tx
-                        .send(Message {
-                            from: self.info(),
-                            contents: MessageData::FoundPeers {
-                                id,
-                                peers: self.find_closest_peers(&hash, &self.k.clone()),
-                            },
-                        })
-                        .await?;
+                    msg.from.send(Message {
+                        from: self.info(),
+                        contents: MessageData::FoundPeers {
+                            id,
+                            peers: self.find_closest_peers(&hash, &self.k.clone()),
+                        },
+                    })?;
                 }
             }
             MessageData::FoundPeers { id, peers } => {
-                let mut found_self = 0;
                 for peer in peers {
-                    if peer.id == self.id {
-                        found_self += 1;
-                    }
-                    self.add_peer(&peer);
+                    self.learn_peer(&peer)?;
                 }
-                if let Some((expect_hash, send_to, ttl)) = self
-                    .m_id_to_find_id
-                    .remove(&id)
-                    .map(|x| self.waiting_finds.remove(&x))
-                    .flatten()
-                {
-                    if ttl > 0 {
-                        self.find_with_ttl(&expect_hash, &send_to, &(ttl - found_self))
-                            .await?;
-                    } else {
-                        // Not found; exceeded TTL.
-                        send_to.send(None).await?;
+                if let Some(find_id) = self.m_id_to_find_id.remove(&id) {
+                    if let Some(find) = self.waiting_finds.get_mut(&find_id) {
+                        find.pending -= 1;
+                        if find.ttl > 0 {
+                            find.ttl -= 1;
+                            self.retry_find(find_id)?;
+                        } else if find.pending == 0 {
+                            // Not found; exceeded TTL. Dropping `send_to` ends the find.
+                            self.waiting_finds.remove(&find_id);
+                        }
                     }
                 } else {
                     // Not called for
@@ -303,65 +779,288 @@ impl Peer {
                 data,
                 propagate,
             } => {
-                if let Some((expect_hash, send_to, _)) = self
+                if let Some(find_id) = self.m_id_to_find_id.remove(&id) {
+                    if let Some(find) = self.waiting_finds.get_mut(&find_id) {
+                        find.pending -= 1;
+                        if hash::verify(&find.hash, &data) {
+                            reply(&find.send_to, (find.hash, data.clone()));
+                            self.waiting_finds.remove(&find_id);
+                        } else {
+                            // Wrong data. Treat it as if the peer hadn't answered.
+                            self.retry_find(find_id)?;
+                        }
+                    }
+                }
+                if let Some(algorithm) = propagate {
+                    self.store(algorithm, data)?;
+                }
+            }
+            MessageData::Announce {
+                key,
+                provider,
+                value,
+            } => {
+                self.add_record(key, provider, value)?;
+            }
+            MessageData::FindRecords { id, key } => {
+                if let Some(records) = self.records.get(&key) {
+                    msg.from.send(Message {
+                        from: self.info(),
+                        contents: MessageData::FoundRecords {
+                            id,
+                            records: records.iter().map(|(k, v)| (*k, v.clone())).collect(),
+                        },
+                    })?;
+                } else {
+                    msg.from.send(Message {
+                        from: self.info(),
+                        contents: MessageData::FoundPeers {
+                            id,
+                            peers: self.find_closest_peers(&key, &self.k.clone()),
+                        },
+                    })?;
+                }
+            }
+            MessageData::ExecuteRequest {
+                id,
+                hash,
+                mana_limit,
+                memo_limit,
+            } => {
+                let (reply, rx) = oneshot::channel();
+                let job = ExecuteJob {
+                    hash,
+                    mana_limit,
+                    memo_limit,
+                    reply,
+                };
+                if let Some(Err(mpsc::error::SendError(job))) =
+                    self.executor.as_ref().map(|x| x.send(job))
+                {
+                    let _ = job.reply.send(Err("Executor stopped".to_string()));
+                }
+                // Blocks can take a while to run, so wait for the result in another task.
+                let from = self.info();
+                tokio::spawn(async move {
+                    let result = rx
+                        .await
+                        .unwrap_or_else(|_| Err("Not an executing node".to_string()));
+                    let _ = msg.from.send(Message {
+                        from,
+                        contents: MessageData::ExecuteResult { id, result },
+                    });
+                });
+            }
+            MessageData::ExecuteResult { id, result } => {
+                if let Some((_, reply)) = self.waiting_executions.remove(&id) {
+                    let _ = reply.send(result);
+                }
+            }
+            MessageData::RelayRequest => {
+                if self.relayed.len() < MAX_RELAYED || self.relayed.contains_key(&msg.from.id) {
+                    self.relayed.insert(msg.from.id, msg.from.direct());
+                }
+            }
+            MessageData::RelayedMessage { to, message } => {
+                if let MessageData::RelayedMessage { .. } = message.contents {
+                    return Err("Relayed messages can't be nested".into());
+                }
+                if to == self.id {
+                    self.handle_msg(*message)?;
+                } else if let Some(peer) = self.relayed.get(&to) {
+                    peer.send(Message {
+                        from: self.info(),
+                        contents: MessageData::RelayedMessage { to, message },
+                    })?;
+                }
+            }
+            MessageData::FoundRecords { id, records } => {
+                if let Some(find) = self
                     .m_id_to_find_id
                     .remove(&id)
-                    .map(|x| self.waiting_finds.remove(&x))
-                    .flatten()
+                    .and_then(|x| self.waiting_finds.remove(&x))
                 {
-                    assert!(expect_hash == self.hash(&data));
-                    send_to.send(Some((expect_hash, data.clone()))).await?;
-                }
-                if propagate {
-                    self.store(data).await?;
+                    for record in records {
+                        reply(&find.send_to, record);
+                    }
                 }
             }
-            MessageData::Stop => self.rx.close(),
         };
         Ok(())
     }
-    pub async fn store(&mut self, data: Box<[u8]>) -> Result<(), Box<dyn Error>> {
-        let h = self.hash(&data);
-        // println!("{} Propg {} | {} = {}", encode_id(&self.id), encode_id(&self.distance_to(&h)), encode_id(&h), hex::encode(&data));
+    pub fn store(&mut self, algorithm: Algorithm, data: Box<[u8]>) -> Result<Id, Box<dyn Error>> {
+        let h = hash::hash_with(algorithm, &data);
         self.store.insert(h, data.clone());
-        let peer = self
-            .find_closest_peers(&h, &1)
-            .first()
-            .cloned()
-            .expect("No peers!");
+        // When standalone, the closest peer is always ourselves.
+        let peer = self.find_closest_peers(&h, &1).remove(0);
         if peer.id != self.id {
-            let id = self.rng.next_u64();
-            peer.tx
-                .send(self.make_msg(MessageData::FoundData {
-                    id,
-                    data,
-                    propagate: true,
-                }))
-                .await?;
+            trace!(hash = %encode_id(&h), to = %encode_id(&peer.id), "Passing on data");
+            self.send_data(&peer, algorithm, data)?;
         } else {
-            // println!("{} Store {} | {} = {}", encode_id(&self.id), encode_id(&self.distance_to(&h)), encode_id(&h), hex::encode(data));
+            trace!(hash = %encode_id(&h), "Storing data");
         }
-        Ok(())
+        Ok(h)
     }
-    pub async fn run_timeout(&mut self, duration: Duration) -> Result<(), Box<dyn Error>> {
-        while let Ok(Some(msg)) = tokio::time::timeout(duration, self.rx.recv()).await {
-            self.handle_msg(msg).await?;
+    /// Announce that we have `value` for `key`.
+    pub fn announce(&mut self, key: Id, value: Box<[u8]>) -> Result<(), Box<dyn Error>> {
+        self.add_record(key, self.id, value)
+    }
+    /// Keep a record, and pass it on if we're not the closest peer to its key that we know of.
+    /// Records we already had aren't passed on again.
+    fn add_record(
+        &mut self,
+        key: Id,
+        provider: Id,
+        value: Box<[u8]>,
+    ) -> Result<(), Box<dyn Error>> {
+        let records = self.records.entry(key).or_default();
+        if records.get(&provider) == Some(&value)
+            || (records.len() >= MAX_PROVIDERS && !records.contains_key(&provider))
+        {
+            return Ok(());
+        }
+        records.insert(provider, value.clone());
+        let peer = self.find_closest_peers(&key, &1).remove(0);
+        if peer.id != self.id {
+            peer.send(self.make_msg(MessageData::Announce {
+                key,
+                provider,
+                value,
+            }))?;
         }
         Ok(())
     }
-    pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
-        while let Some(msg) = self.rx.recv().await {
-            self.handle_msg(msg).await?;
+    /// Ask `peer` to store `data` and pass it on to whoever is closer to it.
+    fn send_data(
+        &mut self,
+        peer: &PeerInfo,
+        algorithm: Algorithm,
+        data: Box<[u8]>,
+    ) -> Result<(), Box<dyn Error>> {
+        let id = self.rng.next_u64();
+        peer.send(self.make_msg(MessageData::FoundData {
+            id,
+            data,
+            propagate: Some(algorithm),
+        }))
+    }
+    pub fn handle_command(&mut self, command: Command) -> Result<(), Box<dyn Error>> {
+        match command {
+            Command::Find { hash, send_to } => self.find(&hash, send_to)?,
+            Command::Store {
+                algorithm,
+                data,
+                reply,
+            } => {
+                let _ = reply.send(self.store(algorithm, data)?);
+            }
+            Command::Announce { key, value } => self.announce(key, value)?,
+            Command::FindRecords { key, send_to } => {
+                self.find_with_ttl(&key, FindKind::Records, &Some(send_to), &5)?
+            }
+            Command::AddPeer { info } => self.learn_peer(&info)?,
+            Command::ClosestPeers {
+                hash,
+                amount,
+                reply,
+            } => {
+                let mut peers = self.find_closest_peers(&hash, &(amount + 1));
+                peers.retain(|x| x.id != self.id);
+                peers.truncate(amount as usize);
+                let _ = reply.send(peers);
+            }
+            Command::Execute {
+                peer,
+                hash,
+                mana_limit,
+                memo_limit,
+                reply,
+            } => {
+                let id = self.rng.next_u64();
+                let msg = self.make_msg(MessageData::ExecuteRequest {
+                    id,
+                    hash,
+                    mana_limit,
+                    memo_limit,
+                });
+                match peer.send(msg) {
+                    Ok(()) => {
+                        self.waiting_executions.insert(id, (Instant::now(), reply));
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e.to_string()));
+                    }
+                }
+            }
+            Command::SetExecutor { jobs } => self.executor = Some(jobs),
+            Command::SetRelay { relay } => {
+                self.relay = Some(relay.direct());
+                // Sending this also opens the way for the relay's messages to get through.
+                relay.send(self.make_msg(MessageData::RelayRequest))?;
+            }
+            Command::Bootstrap { seeds, send_to } => self.bootstrap(&seeds, Some(send_to))?,
+            Command::RefreshBuckets { max_age } => self.refresh_buckets(max_age)?,
         }
         Ok(())
     }
+    /// Serve remote requests and the commands from `commands` until all
+    /// the handles to this peer are dropped.
+    pub async fn run(mut self, mut commands: mpsc::Receiver<Command>) {
+        let mut refresh = tokio::time::interval(BUCKET_REFRESH_INTERVAL);
+        let mut expire = tokio::time::interval(FIND_TIMEOUT);
+        // Errors only happen when sending to peers that went away or are too busy,
+        // which is not a reason to stop serving everyone else.
+        loop {
+            // Polling in a fixed order keeps simulations reproducible.
+            tokio::select! {
+                biased;
+                Some(msg) = self.rx.recv() => {
+                    let _ = self.handle_msg(msg);
+                }
+                command = commands.recv() => match command {
+                    Some(command) => {
+                        let _ = self.handle_command(command);
+                    }
+                    None => return,
+                },
+                _ = refresh.tick() => {
+                    let _ = self.refresh_buckets(BUCKET_REFRESH_AGE);
+                    // Keep the way open for the relay to reach us.
+                    if let Some(relay) = &self.relay {
+                        let _ = relay.send(self.make_msg(MessageData::RelayRequest));
+                    }
+                }
+                _ = expire.tick() => self.expire_finds(),
+            }
+        }
+    }
+    /// Run this peer in its own task.
+    pub fn spawn(self) -> PeerHandle {
+        let (commands, rx) = mpsc::channel(QUEUE_SIZE);
+        let info = self.info();
+        let span = debug_span!("peer", id = %encode_id(&info.id));
+        tokio::spawn(self.run(rx).instrument(span));
+        PeerHandle { commands, info }
+    }
     pub fn new(rng: &mut dyn rand::RngCore) -> Peer {
-        let (tx, rx) = mpsc::channel(100);
-
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        Self::with_channel(rng, tx, rx)
+    }
+    /// Create a peer that receives messages from `rx`, and tells others to send them to `tx`.
+    ///
+    /// Normally both ends belong to the same channel, but they can be different
+    /// so that something sits in between, like the router in `sim`.
+    pub fn with_channel(
+        rng: &mut dyn rand::RngCore,
+        tx: mpsc::Sender<Message>,
+        rx: mpsc::Receiver<Message>,
+    ) -> Peer {
         Peer {
-            store: HashMap::new(),
+            store: BTreeMap::new(),
+            records: BTreeMap::new(),
             k: 20,
             buckets: std::array::from_fn(|_x| Vec::new()),
+            bucket_lookups: [None; 256],
             rx,
             tx,
             id: ethnum::U256::from_le_bytes(std::array::from_fn(|_x| {
@@ -371,13 +1070,65 @@ impl Peer {
             peer_distance: HashMap::new(),
             waiting_finds: HashMap::new(),
             m_id_to_find_id: HashMap::new(),
+            waiting_executions: HashMap::new(),
+            executor: None,
+            relay: None,
+            relayed: HashMap::new(),
             rng: Box::new(rand_chacha::ChaCha20Rng::seed_from_u64(rng.next_u64())),
+            started_at: Instant::now(),
         }
     }
     pub fn info(&self) -> PeerInfo {
         PeerInfo {
-            id: self.id.clone(),
+            id: self.id,
             tx: self.tx.clone(),
+            relay: self.relay.clone().map(Box::new),
         }
     }
 }
+
+/// Send the result of a find to whoever asked for it, if anyone is still listening.
+fn reply(send_to: &Option<FindSender>, value: (Id, Box<[u8]>)) {
+    if let Some(send_to) = send_to {
+        let _ = send_to.send(value);
+    }
+}
+
+/// Start `amount` peers that only live in this process and know about each other,
+/// so that there's a network to bootstrap from without a real transport.
+///
+/// The peers keep running for as long as the returned handles are alive.
+pub async fn spawn_local_seeds(
+    rng: &mut dyn rand::RngCore,
+    amount: usize,
+) -> Result<Vec<PeerHandle>, Box<dyn Error>> {
+    let seeds: Vec<_> = (0..amount).map(|_| Peer::new(rng).spawn()).collect();
+    for seed in &seeds {
+        for other in &seeds {
+            if seed.id() != other.id() {
+                other.add_peer(&seed.info()).await?;
+            }
+        }
+    }
+    Ok(seeds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_relayed_messages_are_rejected() {
+        let mut peer = Peer::new(&mut rand::thread_rng());
+        let id = peer.info().id();
+        let inner = peer.make_msg(MessageData::RelayedMessage {
+            to: id,
+            message: Box::new(peer.make_msg(MessageData::RelayRequest)),
+        });
+        let outer = peer.make_msg(MessageData::RelayedMessage {
+            to: id,
+            message: Box::new(inner),
+        });
+        assert!(peer.handle_msg(outer).is_err());
+    }
+}
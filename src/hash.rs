@@ -0,0 +1,96 @@
+//! The hash functions that ids are made with.
+//!
+//! An id is a 256-bit digest whose lowest byte is replaced by a tag saying which
+//! algorithm made it, so that the network can move to another hash function
+//! without old and new ids getting mixed up. The tag goes in the lowest byte
+//! because it barely affects XOR distances, so ids of every algorithm are spread
+//! over the whole network.
+//!
+//! The tag takes the place of the digest's first byte, so ids only keep 248 bits
+//! of the digest. That leaves 124 bits of collision resistance, which is plenty
+//! for ids, and keeps them the same size as a digest.
+use blake2::Digest;
+
+use crate::types::Id;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Algorithm {
+    Blake2s256,
+    Blake3,
+    Sha256,
+}
+
+/// What new ids are made with.
+pub const DEFAULT: Algorithm = Algorithm::Blake2s256;
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 3] = [Algorithm::Blake2s256, Algorithm::Blake3, Algorithm::Sha256];
+
+    pub fn tag(self) -> u8 {
+        match self {
+            Algorithm::Blake2s256 => 0,
+            Algorithm::Blake3 => 1,
+            Algorithm::Sha256 => 2,
+        }
+    }
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.tag() == tag)
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Blake2s256 => "blake2s256",
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.name() == name)
+    }
+    /// The untagged digest of `data`.
+    pub fn digest(self, data: &[u8]) -> [u8; 32] {
+        match self {
+            Algorithm::Blake2s256 => blake2::Blake2s256::digest(data).into(),
+            Algorithm::Blake3 => blake3::hash(data).into(),
+            Algorithm::Sha256 => sha2::Sha256::digest(data).into(),
+        }
+    }
+}
+
+/// The id of `data` when hashed with `algorithm`: its digest with the first byte replaced by the tag.
+pub fn hash_with(algorithm: Algorithm, data: &[u8]) -> Id {
+    let mut digest = algorithm.digest(data);
+    digest[0] = algorithm.tag();
+    Id::from_le_bytes(digest)
+}
+
+/// The id of `data` when hashed with the default algorithm.
+pub fn hash(data: &[u8]) -> Id {
+    hash_with(DEFAULT, data)
+}
+
+/// The algorithm that made `id`, if it's one we know.
+pub fn algorithm_of(id: &Id) -> Option<Algorithm> {
+    Algorithm::from_tag(id.to_le_bytes()[0])
+}
+
+/// Whether `id` is the id of `data`, whatever algorithm it was made with.
+pub fn verify(id: &Id, data: &[u8]) -> bool {
+    algorithm_of(id).is_some_and(|algorithm| hash_with(algorithm, data) == *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_replaces_first_digest_byte() {
+        for algorithm in Algorithm::ALL {
+            let digest = algorithm.digest(b"abc");
+            let id = hash_with(algorithm, b"abc").to_le_bytes();
+            assert_eq!(id[0], algorithm.tag());
+            assert_eq!(id[1..], digest[1..]);
+            assert_eq!(algorithm_of(&Id::from_le_bytes(id)), Some(algorithm));
+            assert!(verify(&Id::from_le_bytes(id), b"abc"));
+        }
+    }
+}
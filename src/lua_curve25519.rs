@@ -1,59 +1,128 @@
-use std::num::TryFromIntError;
+use std::sync::OnceLock;
 
-use blake2::Blake2s256;
+use crate::{
+    hash::{self, Algorithm},
+    script_vm::{charge_mana, in_block},
+    value::Value,
+};
+use bulletproofs::{BulletproofGens, PedersenGens, RangeProof};
 use curve25519_dalek::{
     edwards::{CompressedEdwardsY, EdwardsPoint},
+    ristretto::{CompressedRistretto, RistrettoPoint},
     scalar::Scalar,
+    traits::MultiscalarMul,
 };
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use merlin::Transcript;
 use mlua::prelude::*;
-use rand::SeedableRng;
+use rand::{RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 
+/// Mana charged for native operations, about what running VM instructions for as long would cost.
+pub const POINT_MUL_MANA: u64 = 5_000;
+/// Multiscalar multiplication is charged per term, and is cheaper than multiplying one by one.
+pub const MULTISCALAR_MUL_MANA_PER_TERM: u64 = 1_500;
+pub const SIGN_MANA: u64 = 2_500;
+pub const VERIFY_MANA: u64 = 5_000;
+/// Batch verification is charged per signature, and is cheaper than verifying one by one.
+pub const BATCH_VERIFY_MANA_PER_SIGNATURE: u64 = 2_500;
+/// Range proofs are charged per bit of the range.
+pub const RANGE_PROOF_MANA_PER_BIT: u64 = 3_000;
+/// Hashing is charged per 64 bytes of data, counting at least one.
+pub const HASH_MANA_PER_BLOCK: u64 = 50;
+
 #[derive(Clone, Debug, FromLua)]
 pub struct LuaEdwardsPoint(pub EdwardsPoint);
 
 #[derive(Clone, Debug)]
 pub struct LuaScalar(pub Scalar);
 
+/// An element of the Ristretto group, which has prime order, unlike the Edwards curve
+/// under it. Every element has exactly one encoding.
+#[derive(Clone, Debug, FromLua)]
+pub struct LuaRistretto(pub RistrettoPoint);
+
 #[derive(Clone, Debug)]
 pub struct LuaU256(pub ethnum::U256);
 
+/// A byte string. Unlike Lua strings, these serialize as hex, and convert to and from the other types.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuaBytes(pub Vec<u8>);
+
 #[derive(Clone, Debug, FromLua)]
 pub struct LuaRng(ChaCha20Rng);
 
+/// A proof that the value in a Pedersen commitment fits in some number of bits.
+#[derive(Clone, Debug, FromLua)]
+pub struct LuaRangeProof(pub RangeProof);
+
+/// An Ed25519 secret key.
+#[derive(Clone, Debug, FromLua)]
+pub struct LuaSigningKey(pub SigningKey);
+
+/// An Ed25519 public key.
+#[derive(Clone, Debug, FromLua)]
+pub struct LuaPublicKey(pub VerifyingKey);
+
+/// An Ed25519 signature. Signing is deterministic, so the same key and message
+/// always give the same signature.
+#[derive(Clone, Debug, FromLua)]
+pub struct LuaSignature(pub Signature);
+
+/// The number in `value` as a `u64`, if it's a number. Fractions, NaN, negative
+/// numbers and numbers from 2^64 up, which don't fit in a `u64`, are errors.
+fn whole_number(value: &mlua::Value, to: &str) -> Option<LuaResult<u64>> {
+    let n = match *value {
+        mlua::Value::Integer(n) => n as f64,
+        mlua::Value::Number(n) => n,
+        _ => return None,
+    };
+    Some(if n.is_nan() || n.fract() != 0.0 {
+        Err(format!("Only whole numbers can be turned into a {}", to).into_lua_err())
+    } else if n < 0.0 {
+        Err(format!("Negative numbers can't be turned into a {}", to).into_lua_err())
+    } else if n >= u64::MAX as f64 {
+        Err(format!("Numbers from 2^64 up can't be turned into a {}", to).into_lua_err())
+    } else {
+        Ok(match *value {
+            mlua::Value::Integer(n) => n as u64,
+            _ => n as u64,
+        })
+    })
+}
+
 impl<'a> FromLua<'a> for LuaScalar {
     fn from_lua(value: mlua::Value<'a>, _lua: &'a Lua) -> Result<Self, LuaError> {
-        if let Some(n) = value.as_integer() {
-            let n: u64 = n
-                .try_into()
-                .map_err(|x: TryFromIntError| x.into_lua_err())?;
-            return Ok(LuaScalar(Scalar::from(n)));
-        }
-        if let Some(n) = value.as_number() {
-            // TODO try to do this?
-            let n: u64 = n as u64;
-            return Ok(LuaScalar(Scalar::from(n)));
+        if let Some(n) = whole_number(&value, "scalar") {
+            return Ok(LuaScalar(Scalar::from(n?)));
         }
         if let Some(n) = value
             .as_userdata()
-            .map(|s| s.borrow::<LuaScalar>().ok())
-            .flatten()
+            .and_then(|s| s.borrow::<LuaScalar>().ok())
         {
             return Ok(n.clone());
         }
+        if let Some(n) = value.as_userdata().and_then(|s| s.borrow::<LuaU256>().ok()) {
+            return Ok(LuaScalar(Scalar::from_bytes_mod_order(n.0.to_le_bytes())));
+        }
         if let Some(n) = value
             .as_userdata()
-            .map(|s| s.borrow::<LuaU256>().ok())
-            .flatten()
+            .and_then(|s| s.borrow::<LuaBytes>().ok())
         {
-            return Ok(LuaScalar(Scalar::from_bytes_mod_order(n.0.to_le_bytes())));
+            // 64 bytes are enough for the result to be uniform when they are.
+            if let Ok(bytes) = n.0.as_slice().try_into() {
+                return Ok(LuaScalar(Scalar::from_bytes_mod_order(bytes)));
+            }
+            if let Ok(bytes) = n.0.as_slice().try_into() {
+                return Ok(LuaScalar(Scalar::from_bytes_mod_order_wide(bytes)));
+            }
+            return Err("Only 32 or 64 bytes can be turned into a scalar".into_lua_err());
         }
-        if let Some(_n) = value
+        if value
             .as_userdata()
-            .map(|s| s.borrow::<LuaEdwardsPoint>().ok())
-            .flatten()
+            .is_some_and(|s| s.is::<LuaEdwardsPoint>() || s.is::<LuaRistretto>())
         {
-            todo!("Try to convert point!");
+            return Err("Points can't be turned into scalars".into_lua_err());
         }
         if let Some(_n) = value.as_userdata() {
             return Err(LuaError::FromLuaConversionError {
@@ -62,89 +131,116 @@ impl<'a> FromLua<'a> for LuaScalar {
                 message: value.to_string().ok(),
             });
         }
-        return Err(LuaError::FromLuaConversionError {
+        Err(LuaError::FromLuaConversionError {
             from: value.type_name(),
             to: "LuaScalar",
             message: None,
-        });
+        })
     }
 }
 
 impl<'a> FromLua<'a> for LuaU256 {
     fn from_lua(value: mlua::Value<'a>, _lua: &'a Lua) -> Result<Self, LuaError> {
-        if let Some(n) = value.as_integer() {
-            let n: u64 = n
-                .try_into()
-                .map_err(|x: TryFromIntError| x.into_lua_err())?;
-            return Ok(LuaU256(ethnum::U256::from(n)));
+        if let Some(n) = whole_number(&value, "U256") {
+            return Ok(LuaU256(ethnum::U256::from(n?)));
         }
-        if let Some(n) = value.as_number() {
-            // TODO try to do this?
-            let n: u64 = n as u64;
-            return Ok(LuaU256(ethnum::U256::from(n)));
+        if let Some(n) = value.as_userdata().and_then(|s| s.borrow::<LuaU256>().ok()) {
+            return Ok(n.clone());
         }
         if let Some(n) = value
             .as_userdata()
-            .map(|s| s.borrow::<LuaU256>().ok())
-            .flatten()
+            .and_then(|s| s.borrow::<LuaScalar>().ok())
         {
-            return Ok(n.clone());
+            return Ok(LuaU256(ethnum::U256::from_le_bytes(*n.0.as_bytes())));
         }
         if let Some(n) = value
             .as_userdata()
-            .map(|s| s.borrow::<LuaScalar>().ok())
-            .flatten()
+            .and_then(|s| s.borrow::<LuaBytes>().ok())
         {
-            return Ok(LuaU256(ethnum::U256::from_le_bytes(n.0.as_bytes().clone())));
+            let bytes =
+                n.0.as_slice()
+                    .try_into()
+                    .map_err(|_| "Only 32 bytes can be turned into a U256".into_lua_err())?;
+            return Ok(LuaU256(ethnum::U256::from_le_bytes(bytes)));
         }
         if let Some(_n) = value.as_userdata() {
             return Err(LuaError::FromLuaConversionError {
                 from: value.type_name(),
-                to: "LuaScalar",
+                to: "LuaU256",
                 message: value.to_string().ok(),
             });
         }
-        return Err(LuaError::FromLuaConversionError {
+        Err(LuaError::FromLuaConversionError {
             from: value.type_name(),
-            to: "LuaScalar",
+            to: "LuaU256",
             message: None,
-        });
+        })
     }
 }
 
-pub fn mul_fn<'lua>(
-    lua: &'lua Lua,
-    this: mlua::Value<'lua>,
-    other: mlua::Value<'lua>,
-) -> LuaResult<mlua::Value<'lua>> {
-    if let Some(this) = this
-        .as_userdata()
-        .map(|s| s.borrow::<LuaEdwardsPoint>().ok())
-        .flatten()
-    {
-        if let Some(_other) = other
+impl<'a> FromLua<'a> for LuaBytes {
+    fn from_lua(value: mlua::Value<'a>, _lua: &'a Lua) -> Result<Self, LuaError> {
+        if let mlua::Value::String(s) = &value {
+            return Ok(LuaBytes(s.as_bytes().to_vec()));
+        }
+        if let Some(n) = value
             .as_userdata()
-            .map(|s| s.borrow::<LuaEdwardsPoint>().ok())
-            .flatten()
+            .and_then(|s| s.borrow::<LuaBytes>().ok())
         {
-            Err("Can't multiply two points together".into_lua_err())
-        } else {
-            Ok(
-                LuaEdwardsPoint(this.0 * LuaScalar::from_lua(other.clone(), lua)?.0)
-                    .into_lua(lua)?,
-            )
+            return Ok(n.clone());
         }
-    } else {
-        let this = LuaScalar::from_lua(this.clone(), lua)?;
-        if let Some(other) = other
+        if let Some(n) = value.as_userdata().and_then(|s| s.borrow::<LuaU256>().ok()) {
+            return Ok(LuaBytes(n.0.to_le_bytes().to_vec()));
+        }
+        if let Some(n) = value
             .as_userdata()
-            .map(|s| s.borrow::<LuaEdwardsPoint>().ok())
-            .flatten()
+            .and_then(|s| s.borrow::<LuaScalar>().ok())
         {
-            Ok(LuaEdwardsPoint(this.0 * other.0).into_lua(lua)?)
-        } else {
-            Ok(LuaScalar(this.0 * LuaScalar::from_lua(other.clone(), lua)?.0).into_lua(lua)?)
+            return Ok(LuaBytes(n.0.to_bytes().to_vec()));
         }
+        Err(LuaError::FromLuaConversionError {
+            from: value.type_name(),
+            to: "LuaBytes",
+            message: None,
+        })
+    }
+}
+
+pub fn mul_fn<'lua>(
+    lua: &'lua Lua,
+    this: mlua::Value<'lua>,
+    other: mlua::Value<'lua>,
+) -> LuaResult<mlua::Value<'lua>> {
+    let edwards = |x: &mlua::Value| {
+        x.as_userdata()
+            .and_then(|s| s.borrow::<LuaEdwardsPoint>().ok())
+            .map(|x| x.0)
+    };
+    let ristretto = |x: &mlua::Value| {
+        x.as_userdata()
+            .and_then(|s| s.borrow::<LuaRistretto>().ok())
+            .map(|x| x.0)
+    };
+    let is_point = |x: &mlua::Value| edwards(x).is_some() || ristretto(x).is_some();
+    if is_point(&this) && is_point(&other) {
+        return Err("Can't multiply two points together".into_lua_err());
+    }
+    if is_point(&this) || is_point(&other) {
+        charge_mana(POINT_MUL_MANA)?;
+    }
+    // Multiplication is commutative, so put the point first if there is one.
+    let (this, other) = if is_point(&other) {
+        (other, this)
+    } else {
+        (this, other)
+    };
+    if let Some(this) = edwards(&this) {
+        LuaEdwardsPoint(this * LuaScalar::from_lua(other, lua)?.0).into_lua(lua)
+    } else if let Some(this) = ristretto(&this) {
+        LuaRistretto(this * LuaScalar::from_lua(other, lua)?.0).into_lua(lua)
+    } else {
+        let this = LuaScalar::from_lua(this, lua)?;
+        LuaScalar(this.0 * LuaScalar::from_lua(other, lua)?.0).into_lua(lua)
     }
 }
 
@@ -165,7 +261,7 @@ impl LuaUserData for LuaScalar {
         methods.add_meta_method(
             "__concat",
             |_lua: &'lua Lua, this: &LuaScalar, other: mlua::Value<'lua>| {
-                let other = other.to_string().unwrap();
+                let other = other.to_string()?;
                 let this = hex::encode(this.0.to_bytes());
                 Ok(this + &other)
             },
@@ -176,12 +272,7 @@ impl LuaUserData for LuaScalar {
                 hex::encode(this.0.to_bytes())
             ))
         });
-        methods.add_method("__serpent", |_lua, this: &LuaScalar, ()| {
-            Ok(format!(
-                "crypto.Scalar.deserialize({:?})",
-                hex::encode(this.0.to_bytes())
-            ))
-        });
+        add_encoding_methods(methods);
     }
 }
 impl LuaUserData for LuaEdwardsPoint {
@@ -212,23 +303,38 @@ impl LuaUserData for LuaEdwardsPoint {
                 hex::encode(this.0.compress().to_bytes())
             ))
         });
-        methods.add_method("__serpent", |_lua, this: &LuaEdwardsPoint, ()| {
+        add_encoding_methods(methods);
+    }
+}
+
+impl LuaUserData for LuaRistretto {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_function("__add", |_, (this, other): (LuaRistretto, LuaRistretto)| {
+            Ok(LuaRistretto(this.0 + other.0))
+        });
+        methods.add_meta_function("__sub", |_, (this, other): (LuaRistretto, LuaRistretto)| {
+            Ok(LuaRistretto(this.0 - other.0))
+        });
+        methods.add_meta_function("__unm", |_, this: LuaRistretto| Ok(LuaRistretto(-this.0)));
+        methods.add_meta_function("__eq", |_, (this, other): (LuaRistretto, LuaRistretto)| {
+            Ok(this.0 == other.0)
+        });
+        methods.add_meta_function("__mul", |lua, (this, other): (mlua::Value, mlua::Value)| {
+            mul_fn(lua, this, other)
+        });
+        methods.add_meta_method("__tostring", |_lua, this: &LuaRistretto, ()| {
             Ok(format!(
-                "crypto.Point.deserialize({:?})",
+                "crypto.Ristretto(0x{})",
                 hex::encode(this.0.compress().to_bytes())
             ))
-        })
+        });
+        add_encoding_methods(methods);
     }
 }
 
 impl LuaUserData for LuaU256 {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("__serpent", |_lua, this: &LuaU256, ()| {
-            Ok(format!(
-                "crypto.U256.deserialize({:?})",
-                hex::encode(this.0.to_le_bytes())
-            ))
-        });
+        add_encoding_methods(methods);
         methods.add_meta_method("__tostring", |_lua, this: &LuaU256, ()| {
             Ok(format!("crypto.U256({:#X})", this.0))
         });
@@ -241,35 +347,458 @@ impl LuaUserData for LuaU256 {
         methods.add_meta_function("__le", |_, (this, other): (LuaU256, LuaU256)| {
             Ok(this.0 <= other.0)
         });
-        methods.add_meta_function("__add", |_, (this, other): (LuaU256, LuaU256)| {
-            Ok(LuaU256(this.0 + other.0))
-        });
-        methods.add_meta_function("__sub", |_, (this, other): (LuaU256, LuaU256)| {
-            Ok(LuaU256(this.0 - other.0))
+        // Operators fail instead of overflowing or dividing by zero. The
+        // `checked_`, `wrapping_` and `saturating_` methods handle that themselves.
+        for (name, op) in U256_OPERATORS {
+            methods.add_meta_function(name, move |_, (this, other): (LuaU256, LuaU256)| {
+                op.1(this.0, other.0)
+                    .map(LuaU256)
+                    .ok_or_else(|| op.0.into_lua_err())
+            });
+            methods.add_function(
+                format!("checked_{}", &name[2..]),
+                move |_, (this, other): (LuaU256, LuaU256)| Ok(op.1(this.0, other.0).map(LuaU256)),
+            );
+        }
+        // Unsigned numbers can only be negated if they're 0.
+        methods.add_meta_function("__unm", |_, this: LuaU256| match this.0 {
+            ethnum::U256::ZERO => Ok(this),
+            _ => Err("U256 overflow".into_lua_err()),
         });
-        methods.add_meta_function("__mul", |_, (this, other): (LuaU256, LuaU256)| {
-            Ok(LuaU256(this.0 * other.0))
+        for (name, op) in U256_WRAPPING {
+            methods.add_function(name, move |_, (this, other): (LuaU256, LuaU256)| {
+                Ok(LuaU256(op(this.0, other.0)))
+            });
+        }
+        for (name, op) in U256_SATURATING {
+            methods.add_function(name, move |_, (this, other): (LuaU256, LuaU256)| {
+                Ok(LuaU256(op(this.0, other.0)))
+            });
+        }
+        methods.add_function("wrapping_neg", |_, this: LuaU256| {
+            Ok(LuaU256(this.0.wrapping_neg()))
         });
-        methods.add_meta_function("__div", |_, (this, other): (LuaU256, LuaU256)| {
-            Ok(LuaU256(this.0 / other.0))
+        // Bit operations. Shifting by 256 bits or more gives 0.
+        for (name, op) in U256_BITWISE {
+            methods.add_function(name, move |_, (this, other): (LuaU256, LuaU256)| {
+                Ok(LuaU256(op(this.0, other.0)))
+            });
+        }
+        methods.add_function("bnot", |_, this: LuaU256| Ok(LuaU256(!this.0)));
+        // The number in `base`, from 2 to 36, which is 10 by default. No prefix is added.
+        methods.add_method("to_string", |_, this: &LuaU256, base: Option<u32>| {
+            let base = base.unwrap_or(10);
+            if !(2..=36).contains(&base) {
+                return Err(format!("Invalid base {}", base).into_lua_err());
+            }
+            Ok(u256_to_string(this.0, base))
         });
         methods.add_meta_function(
             "__concat",
             |_lua: &'lua Lua, (this, other): (mlua::Value<'lua>, mlua::Value<'lua>)| {
-                let other = other.to_string().unwrap();
-                let this = this.to_string().unwrap();
+                let other = other.to_string()?;
+                let this = this.to_string()?;
                 Ok(this + &other)
             },
         );
     }
 }
 
+type U256Operator = fn(ethnum::U256, ethnum::U256) -> Option<ethnum::U256>;
+type U256Function = fn(ethnum::U256, ethnum::U256) -> ethnum::U256;
+
+/// Operators of `U256`, with the error they raise when they have no result.
+const U256_OPERATORS: [(&str, (&str, U256Operator)); 6] = [
+    ("__add", ("U256 overflow", ethnum::U256::checked_add)),
+    ("__sub", ("U256 overflow", ethnum::U256::checked_sub)),
+    ("__mul", ("U256 overflow", ethnum::U256::checked_mul)),
+    (
+        "__div",
+        ("U256 division by zero", ethnum::U256::checked_div),
+    ),
+    (
+        "__mod",
+        ("U256 division by zero", ethnum::U256::checked_rem),
+    ),
+    ("__pow", ("U256 overflow", u256_checked_pow)),
+];
+
+const U256_WRAPPING: [(&str, U256Function); 4] = [
+    ("wrapping_add", ethnum::U256::wrapping_add),
+    ("wrapping_sub", ethnum::U256::wrapping_sub),
+    ("wrapping_mul", ethnum::U256::wrapping_mul),
+    ("wrapping_pow", u256_wrapping_pow),
+];
+
+const U256_SATURATING: [(&str, U256Function); 4] = [
+    ("saturating_add", ethnum::U256::saturating_add),
+    ("saturating_sub", ethnum::U256::saturating_sub),
+    ("saturating_mul", ethnum::U256::saturating_mul),
+    ("saturating_pow", |base, exponent| {
+        u256_checked_pow(base, exponent).unwrap_or(ethnum::U256::MAX)
+    }),
+];
+
+const U256_BITWISE: [(&str, U256Function); 5] = [
+    ("band", |a, b| a & b),
+    ("bor", |a, b| a | b),
+    ("bxor", |a, b| a ^ b),
+    ("shl", |a, b| match b < 256 {
+        true => a << b.as_u32(),
+        false => ethnum::U256::ZERO,
+    }),
+    ("shr", |a, b| match b < 256 {
+        true => a >> b.as_u32(),
+        false => ethnum::U256::ZERO,
+    }),
+];
+
+fn u256_checked_pow(mut base: ethnum::U256, mut exponent: ethnum::U256) -> Option<ethnum::U256> {
+    let mut result = ethnum::U256::ONE;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.checked_mul(base)?;
+        }
+        exponent >>= 1;
+        if exponent > 0 {
+            base = base.checked_mul(base)?;
+        }
+    }
+    Some(result)
+}
+
+fn u256_wrapping_pow(mut base: ethnum::U256, mut exponent: ethnum::U256) -> ethnum::U256 {
+    let mut result = ethnum::U256::ONE;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result.wrapping_mul(base);
+        }
+        exponent >>= 1;
+        base = base.wrapping_mul(base);
+    }
+    result
+}
+
+fn u256_to_string(mut n: ethnum::U256, base: u32) -> String {
+    let mut digits = Vec::new();
+    loop {
+        let digit = (n % base as u128).as_u32();
+        digits.push(char::from_digit(digit, base).unwrap());
+        n /= base as u128;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+impl LuaUserData for LuaBytes {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method("__len", |_lua, this: &LuaBytes, ()| Ok(this.0.len()));
+        methods.add_meta_function("__eq", |_, (this, other): (LuaBytes, LuaBytes)| {
+            Ok(this == other)
+        });
+        // Either side can also be a string, or anything else that converts to bytes.
+        methods.add_meta_function("__concat", |_, (this, other): (LuaBytes, LuaBytes)| {
+            Ok(LuaBytes([this.0, other.0].concat()))
+        });
+        methods.add_meta_method("__tostring", |_lua, this: &LuaBytes, ()| {
+            Ok(format!("crypto.Bytes(0x{})", hex::encode(&this.0)))
+        });
+        add_encoding_methods(methods);
+        // The bytes as a Lua string.
+        methods.add_method("to_string", |lua, this: &LuaBytes, ()| {
+            lua.create_string(&this.0)
+        });
+        methods.add_method("hex", |_lua, this: &LuaBytes, ()| Ok(hex::encode(&this.0)));
+        // Like `string.sub`: bytes `i` to `j`, counting from 1, with negative
+        // positions counting from the end.
+        methods.add_method(
+            "sub",
+            |_lua, this: &LuaBytes, (i, j): (i64, Option<i64>)| {
+                let len = this.0.len() as i64;
+                let position = |x: i64| if x < 0 { len + x + 1 } else { x };
+                let start = position(i).max(1);
+                let end = position(j.unwrap_or(-1)).min(len);
+                if start > end {
+                    return Ok(LuaBytes(Vec::new()));
+                }
+                Ok(LuaBytes(this.0[start as usize - 1..end as usize].to_vec()))
+            },
+        );
+    }
+}
+
 impl LuaUserData for LuaRng {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(_methods: &mut M) {}
 }
 
-use group::Group;
+impl LuaUserData for LuaRangeProof {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method("__tostring", |_lua, this: &LuaRangeProof, ()| {
+            Ok(format!(
+                "crypto.RangeProof({} bytes)",
+                this.0.to_bytes().len()
+            ))
+        });
+        add_encoding_methods(methods);
+    }
+}
+
+/// Generators for range proofs of up to 64 bits. They take a while to make, so they're made once.
+fn bulletproof_gens() -> &'static BulletproofGens {
+    static GENS: OnceLock<BulletproofGens> = OnceLock::new();
+    GENS.get_or_init(|| BulletproofGens::new(64, 1))
+}
+
+/// Check that range proofs can be made for `bits` bits, which has to be 8, 16, 32 or 64.
+fn range_proof_bits(bits: usize) -> LuaResult<usize> {
+    match bits {
+        8 | 16 | 32 | 64 => Ok(bits),
+        _ => Err(format!(
+            "Range proofs can't be for {} bits, only 8, 16, 32 or 64",
+            bits
+        )
+        .into_lua_err()),
+    }
+}
+
+fn range_proof_transcript() -> Transcript {
+    Transcript::new(b"kelili.range_proof")
+}
+
+impl LuaUserData for LuaSigningKey {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("public_key", |_lua, this: &LuaSigningKey, ()| {
+            Ok(LuaPublicKey(this.0.verifying_key()))
+        });
+        // Keep the key itself out of logs and debug output.
+        methods.add_meta_method("__tostring", |_lua, this: &LuaSigningKey, ()| {
+            Ok(format!(
+                "crypto.SigningKey(public key 0x{})",
+                hex::encode(this.0.verifying_key().as_bytes())
+            ))
+        });
+        // No encoding methods, since anyone who sees the encoding could sign with the key.
+    }
+}
+
+impl LuaUserData for LuaPublicKey {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_function("__eq", |_, (this, other): (LuaPublicKey, LuaPublicKey)| {
+            Ok(this.0 == other.0)
+        });
+        methods.add_meta_method("__tostring", |_lua, this: &LuaPublicKey, ()| {
+            Ok(format!(
+                "crypto.PublicKey(0x{})",
+                hex::encode(this.0.as_bytes())
+            ))
+        });
+        add_encoding_methods(methods);
+    }
+}
+
+impl LuaUserData for LuaSignature {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_function("__eq", |_, (this, other): (LuaSignature, LuaSignature)| {
+            Ok(this.0 == other.0)
+        });
+        methods.add_meta_method("__tostring", |_lua, this: &LuaSignature, ()| {
+            Ok(format!(
+                "crypto.Signature(0x{})",
+                hex::encode(this.0.to_bytes())
+            ))
+        });
+        add_encoding_methods(methods);
+    }
+}
+
+/// The canonical binary encoding of a crypto type. Each value has exactly one
+/// encoding, and `from_bytes` rejects everything that isn't one, so equal values
+/// always hash and sign the same way.
+pub trait Encoding: Sized {
+    /// The name of the type in `crypto`.
+    const NAME: &'static str;
+    /// How errors refer to a value of the type.
+    const DESCRIPTION: &'static str;
+    fn to_bytes(&self) -> Vec<u8>;
+    fn from_bytes(bytes: &[u8]) -> LuaResult<Self>;
+}
+
+/// `x:to_bytes()`, and `__serpent` in terms of it.
+fn add_encoding_methods<'lua, T, M>(methods: &mut M)
+where
+    T: Encoding + LuaUserData,
+    M: LuaUserDataMethods<'lua, T>,
+{
+    methods.add_method("to_bytes", |_lua, this: &T, ()| {
+        Ok(LuaBytes(this.to_bytes()))
+    });
+    methods.add_method("__serpent", |_lua, this: &T, ()| {
+        Ok(format!(
+            "crypto.{}.deserialize({:?})",
+            T::NAME,
+            hex::encode(this.to_bytes())
+        ))
+    });
+}
+
+/// `from_bytes`, and `deserialize`, which takes the encoding in hex.
+fn set_encoding_functions<T: Encoding + LuaUserData + 'static>(table: &LuaTable) -> LuaResult<()> {
+    table.set(
+        "from_bytes",
+        LuaFunction::wrap(|_lua, bytes: LuaBytes| T::from_bytes(&bytes.0)),
+    )?;
+    table.set(
+        "deserialize",
+        LuaFunction::wrap(|_lua, code: String| {
+            let bytes = hex::decode(code)
+                .map_err(|_| format!("{} must be in hex", T::DESCRIPTION).into_lua_err())?;
+            T::from_bytes(&bytes)
+        }),
+    )
+}
+
+/// The hash algorithm called `name`, or the default one.
+fn algorithm_named(name: Option<String>) -> LuaResult<Algorithm> {
+    match name {
+        Some(name) => Algorithm::from_name(&name)
+            .ok_or_else(|| format!("Unknown hash algorithm {:?}", name).into_lua_err()),
+        None => Ok(hash::DEFAULT),
+    }
+}
+
+/// `bytes` as an array of `N` bytes, failing if it isn't exactly that long.
+fn exact<const N: usize>(bytes: &[u8], what: &str) -> LuaResult<[u8; N]> {
+    bytes
+        .try_into()
+        .map_err(|_| format!("{} must be {} bytes", what, N).into_lua_err())
+}
+
+impl Encoding for LuaScalar {
+    const NAME: &'static str = "Scalar";
+    const DESCRIPTION: &'static str = "A scalar";
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes().to_vec()
+    }
+    fn from_bytes(bytes: &[u8]) -> LuaResult<Self> {
+        Option::from(Scalar::from_canonical_bytes(exact(
+            bytes,
+            Self::DESCRIPTION,
+        )?))
+        .map(LuaScalar)
+        .ok_or_else(|| "Scalars must be less than the group order".into_lua_err())
+    }
+}
+
+impl Encoding for LuaEdwardsPoint {
+    const NAME: &'static str = "Point";
+    const DESCRIPTION: &'static str = "A point";
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.compress().to_bytes().to_vec()
+    }
+    fn from_bytes(bytes: &[u8]) -> LuaResult<Self> {
+        // Some points have a second, non-canonical encoding, which decompresses fine.
+        let encoding = CompressedEdwardsY(exact(bytes, Self::DESCRIPTION)?);
+        encoding
+            .decompress()
+            .filter(|x| x.compress() == encoding)
+            .map(LuaEdwardsPoint)
+            .ok_or_else(|| "Invalid point encoding".into_lua_err())
+    }
+}
+
+impl Encoding for LuaRistretto {
+    const NAME: &'static str = "Ristretto";
+    const DESCRIPTION: &'static str = "A Ristretto element";
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.compress().to_bytes().to_vec()
+    }
+    fn from_bytes(bytes: &[u8]) -> LuaResult<Self> {
+        CompressedRistretto(exact(bytes, Self::DESCRIPTION)?)
+            .decompress()
+            .map(LuaRistretto)
+            .ok_or_else(|| "Invalid Ristretto encoding".into_lua_err())
+    }
+}
+
+/// In little-endian, like ids.
+impl Encoding for LuaU256 {
+    const NAME: &'static str = "U256";
+    const DESCRIPTION: &'static str = "A U256";
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_le_bytes().to_vec()
+    }
+    fn from_bytes(bytes: &[u8]) -> LuaResult<Self> {
+        Ok(LuaU256(ethnum::U256::from_le_bytes(exact(
+            bytes,
+            Self::DESCRIPTION,
+        )?)))
+    }
+}
+
+impl Encoding for LuaBytes {
+    const NAME: &'static str = "Bytes";
+    const DESCRIPTION: &'static str = "A byte string";
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+    fn from_bytes(bytes: &[u8]) -> LuaResult<Self> {
+        Ok(LuaBytes(bytes.to_vec()))
+    }
+}
+
+impl Encoding for LuaRangeProof {
+    const NAME: &'static str = "RangeProof";
+    const DESCRIPTION: &'static str = "A range proof";
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+    fn from_bytes(bytes: &[u8]) -> LuaResult<Self> {
+        RangeProof::from_bytes(bytes)
+            .map(LuaRangeProof)
+            .map_err(|_| "Invalid range proof".into_lua_err())
+    }
+}
+
+impl Encoding for LuaPublicKey {
+    const NAME: &'static str = "PublicKey";
+    const DESCRIPTION: &'static str = "A public key";
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.as_bytes().to_vec()
+    }
+    fn from_bytes(bytes: &[u8]) -> LuaResult<Self> {
+        let bytes = exact(bytes, Self::DESCRIPTION)?;
+        VerifyingKey::from_bytes(&bytes)
+            .ok()
+            .filter(|x| x.to_edwards().compress().to_bytes() == bytes)
+            .map(LuaPublicKey)
+            .ok_or_else(|| "Invalid public key".into_lua_err())
+    }
+}
+
+impl Encoding for LuaSignature {
+    const NAME: &'static str = "Signature";
+    const DESCRIPTION: &'static str = "A signature";
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes().to_vec()
+    }
+    fn from_bytes(bytes: &[u8]) -> LuaResult<Self> {
+        let signature = Signature::from_bytes(&exact(bytes, Self::DESCRIPTION)?);
+        // `verify` rejects these anyway, but then each signature would have many encodings.
+        if Scalar::from_canonical_bytes(*signature.s_bytes())
+            .is_none()
+            .into()
+        {
+            return Err("Invalid signature".into_lua_err());
+        }
+        Ok(LuaSignature(signature))
+    }
+}
+
 use group::ff::PrimeField;
+use group::Group;
+use sha2::Digest;
 
 pub fn make_lib<'l>(lua: &'l Lua, modname: String) -> LuaResult<mlua::Value<'l>> {
     assert!(modname == "crypto");
@@ -282,9 +811,27 @@ pub fn make_lib<'l>(lua: &'l Lua, modname: String) -> LuaResult<mlua::Value<'l>>
     crypto.set("Point", point.clone())?;
     crypto.set("Random", random.clone())?;
     crypto.set("U256", u256.clone())?;
+    let signing_key = lua.create_table()?;
+    let public_key = lua.create_table()?;
+    let signature = lua.create_table()?;
+    crypto.set("SigningKey", signing_key.clone())?;
+    crypto.set("PublicKey", public_key.clone())?;
+    crypto.set("Signature", signature.clone())?;
+    let ristretto = lua.create_table()?;
+    crypto.set("Ristretto", ristretto.clone())?;
+    let bytes = lua.create_table()?;
+    let hash_functions = lua.create_table()?;
+    crypto.set("Bytes", bytes.clone())?;
+    crypto.set("hash", hash_functions.clone())?;
+    let pedersen = lua.create_table()?;
+    let range_proof = lua.create_table()?;
+    crypto.set("Pedersen", pedersen.clone())?;
+    crypto.set("RangeProof", range_proof.clone())?;
     scalar.set(
         "random",
-        LuaFunction::wrap(|_lua, mut r: LuaRng| Ok(LuaScalar(Scalar::random(&mut r.0)))),
+        LuaFunction::wrap(|_lua, r: LuaAnyUserData| {
+            Ok(LuaScalar(Scalar::random(&mut r.borrow_mut::<LuaRng>()?.0)))
+        }),
     )?;
     scalar.set(
         "identity",
@@ -299,18 +846,13 @@ pub fn make_lib<'l>(lua: &'l Lua, modname: String) -> LuaResult<mlua::Value<'l>>
         LuaFunction::wrap(|_lua, ()| Ok(LuaScalar(Scalar::MULTIPLICATIVE_GENERATOR))),
     )?;
     scalar.set("from", LuaFunction::wrap(|_lua, v: LuaScalar| Ok(v)))?;
-    scalar.set(
-        "deserialize",
-        LuaFunction::wrap(|_lua, code: String| {
-            Ok(LuaScalar(Scalar::from_bytes_mod_order(
-                hex::decode(&code).unwrap().try_into().unwrap(),
-            )))
-        }),
-    )?;
+    set_encoding_functions::<LuaScalar>(&scalar)?;
     point.set(
         "random",
-        LuaFunction::wrap(|_lua, mut r: LuaRng| {
-            Ok(LuaEdwardsPoint(EdwardsPoint::random(&mut r.0)))
+        LuaFunction::wrap(|_lua, r: LuaAnyUserData| {
+            Ok(LuaEdwardsPoint(EdwardsPoint::random(
+                &mut r.borrow_mut::<LuaRng>()?.0,
+            )))
         }),
     )?;
     point.set(
@@ -321,39 +863,605 @@ pub fn make_lib<'l>(lua: &'l Lua, modname: String) -> LuaResult<mlua::Value<'l>>
         "generator",
         LuaFunction::wrap(|_lua, ()| Ok(LuaEdwardsPoint(<EdwardsPoint as Group>::generator()))),
     )?;
+    set_encoding_functions::<LuaEdwardsPoint>(&point)?;
     point.set(
-        "deserialize",
-        LuaFunction::wrap(|_lua, code: String| {
-            Ok(LuaEdwardsPoint(
-                (CompressedEdwardsY::from_slice(&hex::decode(&code).unwrap()).unwrap())
-                    .decompress()
-                    .unwrap(),
-            ))
-        }),
+        "multiscalar_mul",
+        LuaFunction::wrap(
+            |_lua, (scalars, points): (Vec<LuaScalar>, Vec<LuaEdwardsPoint>)| {
+                if scalars.len() != points.len() {
+                    return Err("Expected as many scalars as points".into_lua_err());
+                }
+                charge_mana(MULTISCALAR_MUL_MANA_PER_TERM * scalars.len() as u64)?;
+                Ok(LuaEdwardsPoint(EdwardsPoint::multiscalar_mul(
+                    scalars.iter().map(|x| x.0),
+                    points.iter().map(|x| x.0),
+                )))
+            },
+        ),
     )?;
+    // Blocks have to give the same result on every node, so they can't use entropy.
     random.set(
         "from_entropy",
-        LuaFunction::wrap(|_lua, ()| Ok(LuaRng(ChaCha20Rng::from_entropy()))),
+        LuaFunction::wrap(|_lua, ()| {
+            if in_block() {
+                return Err("Blocks can't use entropy, use IO.random instead".into_lua_err());
+            }
+            Ok(LuaRng(ChaCha20Rng::from_entropy()))
+        }),
     )?;
-    u256.set(
-        "hash",
-        LuaFunction::wrap(|_lua, code: String| {
-            use blake2::Digest;
-            let mut rng = <Blake2s256 as Digest>::new();
-            rng.update(code);
-            let mut s = [0; 32];
-            Digest::finalize_into(rng, (&mut s).into());
-            Ok(LuaU256(ethnum::U256::from_le_bytes(s)))
+    random.set(
+        "from_seed",
+        LuaFunction::wrap(|_lua, seed: LuaBytes| {
+            let seed = seed
+                .0
+                .as_slice()
+                .try_into()
+                .map_err(|_| "Seeds are 32 bytes long".into_lua_err())?;
+            Ok(LuaRng(ChaCha20Rng::from_seed(seed)))
+        }),
+    )?;
+    // The random numbers of the block with hash `hash`. Different `label`s give
+    // independent streams of numbers for the same block.
+    random.set(
+        "for_block",
+        LuaFunction::wrap(|_lua, (hash, label): (LuaU256, Option<LuaBytes>)| {
+            let mut data = b"kelili.random".to_vec();
+            data.extend_from_slice(&hash.0.to_le_bytes());
+            if let Some(label) = label {
+                data.extend_from_slice(&label.0);
+            }
+            charge_hashing(data.len())?;
+            Ok(LuaRng(ChaCha20Rng::from_seed(
+                Algorithm::Blake2s256.digest(&data),
+            )))
         }),
     )?;
     u256.set(
-        "deserialize",
-        LuaFunction::wrap(|_lua, code: String| {
-            Ok(LuaU256(ethnum::U256::from_le_bytes(
-                hex::decode(&code).unwrap().try_into().unwrap(),
+        "hash",
+        LuaFunction::wrap(|_lua, (data, algorithm): (LuaBytes, Option<String>)| {
+            charge_hashing(data.0.len())?;
+            Ok(LuaU256(hash::hash_with(
+                algorithm_named(algorithm)?,
+                &data.0,
             )))
         }),
     )?;
+    set_encoding_functions::<LuaU256>(&u256)?;
     u256.set("from", LuaFunction::wrap(|_lua, v: LuaU256| Ok(v)))?;
-    Ok(crypto.into_lua(lua)?)
+    // Parses a number in `base`, which is 10 by default. Without a base, a `0x`,
+    // `0o` or `0b` prefix picks one.
+    u256.set(
+        "from_string",
+        LuaFunction::wrap(|_lua, (s, base): (String, Option<u32>)| {
+            let parsed = match base {
+                Some(base) if !(2..=36).contains(&base) => {
+                    return Err(format!("Invalid base {}", base).into_lua_err())
+                }
+                Some(base) => ethnum::U256::from_str_radix(&s, base),
+                None => ethnum::U256::from_str_prefixed(&s),
+            };
+            Ok(LuaU256(parsed.map_err(|_| {
+                format!("Invalid U256 {:?}", s).into_lua_err()
+            })?))
+        }),
+    )?;
+    u256.set("max", LuaU256(ethnum::U256::MAX))?;
+    ristretto.set(
+        "identity",
+        LuaFunction::wrap(|_lua, ()| Ok(LuaRistretto(RistrettoPoint::identity()))),
+    )?;
+    ristretto.set(
+        "generator",
+        LuaFunction::wrap(|_lua, ()| Ok(LuaRistretto(RistrettoPoint::generator()))),
+    )?;
+    ristretto.set(
+        "random",
+        LuaFunction::wrap(|_lua, r: LuaAnyUserData| {
+            let mut bytes = [0; 64];
+            r.borrow_mut::<LuaRng>()?.0.fill_bytes(&mut bytes);
+            Ok(LuaRistretto(RistrettoPoint::from_uniform_bytes(&bytes)))
+        }),
+    )?;
+    set_encoding_functions::<LuaRistretto>(&ristretto)?;
+    // Hash any string or bytes to an element whose discrete log nobody knows.
+    ristretto.set(
+        "hash",
+        LuaFunction::wrap(|_lua, data: LuaBytes| {
+            charge_hashing(data.0.len())?;
+            let digest: [u8; 64] = sha2::Sha512::digest(&data.0).into();
+            Ok(LuaRistretto(RistrettoPoint::from_uniform_bytes(&digest)))
+        }),
+    )?;
+    // The sum of `scalars[i] * points[i]`, computed faster than one by one.
+    ristretto.set(
+        "multiscalar_mul",
+        LuaFunction::wrap(
+            |_lua, (scalars, points): (Vec<LuaScalar>, Vec<LuaRistretto>)| {
+                if scalars.len() != points.len() {
+                    return Err("Expected as many scalars as points".into_lua_err());
+                }
+                charge_mana(MULTISCALAR_MUL_MANA_PER_TERM * scalars.len() as u64)?;
+                Ok(LuaRistretto(RistrettoPoint::multiscalar_mul(
+                    scalars.iter().map(|x| x.0),
+                    points.iter().map(|x| x.0),
+                )))
+            },
+        ),
+    )?;
+    // Strings, U256s and scalars turn into their bytes, U256s in little-endian.
+    bytes.set("from", LuaFunction::wrap(|_lua, v: LuaBytes| Ok(v)))?;
+    set_encoding_functions::<LuaBytes>(&bytes)?;
+    // Each takes the data, and optionally a table with a `key` for keyed hashing
+    // and the `len` of the output in bytes.
+    hash_functions.set(
+        "blake2s",
+        LuaFunction::wrap(|_lua, (data, options): (LuaBytes, Option<LuaTable>)| {
+            let (key, len) = hash_options(options, &data, 32)?;
+            let hash = blake2s_simd::Params::new()
+                .key(&key)
+                .hash_length(len)
+                .hash(&data.0);
+            Ok(LuaBytes(hash.as_bytes().to_vec()))
+        }),
+    )?;
+    hash_functions.set(
+        "blake2b",
+        LuaFunction::wrap(|_lua, (data, options): (LuaBytes, Option<LuaTable>)| {
+            let (key, len) = hash_options(options, &data, 64)?;
+            let hash = blake2b_simd::Params::new()
+                .key(&key)
+                .hash_length(len)
+                .hash(&data.0);
+            Ok(LuaBytes(hash.as_bytes().to_vec()))
+        }),
+    )?;
+    // SHA-256 has no key. Shorter outputs are the start of the digest.
+    hash_functions.set(
+        "sha256",
+        LuaFunction::wrap(|_lua, (data, options): (LuaBytes, Option<LuaTable>)| {
+            let (key, len) = hash_options(options, &data, 32)?;
+            if !key.is_empty() {
+                return Err("SHA-256 can't be keyed".into_lua_err());
+            }
+            Ok(LuaBytes(sha2::Sha256::digest(&data.0)[..len].to_vec()))
+        }),
+    )?;
+    // `value * B + blinding * B_blinding`, which hides `value` as long as `blinding` is random.
+    pedersen.set(
+        "commit",
+        LuaFunction::wrap(|_lua, (value, blinding): (LuaScalar, LuaScalar)| {
+            Ok(LuaRistretto(
+                PedersenGens::default().commit(value.0, blinding.0),
+            ))
+        }),
+    )?;
+    // `B` and `B_blinding`.
+    pedersen.set(
+        "generators",
+        LuaFunction::wrap(|_lua, ()| {
+            let gens = PedersenGens::default();
+            Ok((LuaRistretto(gens.B), LuaRistretto(gens.B_blinding)))
+        }),
+    )?;
+    // Commit to `value` with `blinding`, and prove that it's less than `2^bits`.
+    // Returns the proof and the commitment.
+    range_proof.set(
+        "prove",
+        LuaFunction::wrap(
+            |_lua, (value, blinding, bits, r): (u64, LuaScalar, usize, LuaAnyUserData)| {
+                let bits = range_proof_bits(bits)?;
+                if value.checked_shr(bits as u32).is_some_and(|x| x != 0) {
+                    return Err(format!("{} doesn't fit in {} bits", value, bits).into_lua_err());
+                }
+                charge_mana(RANGE_PROOF_MANA_PER_BIT.saturating_mul(2 * bits as u64))?;
+                let (proof, commitment) = RangeProof::prove_single_with_rng(
+                    bulletproof_gens(),
+                    &PedersenGens::default(),
+                    &mut range_proof_transcript(),
+                    value,
+                    &blinding.0,
+                    bits,
+                    &mut r.borrow_mut::<LuaRng>()?.0,
+                )
+                .map_err(|x| x.to_string().into_lua_err())?;
+                let commitment = commitment
+                    .decompress()
+                    .ok_or_else(|| "Invalid commitment".into_lua_err())?;
+                Ok((LuaRangeProof(proof), LuaRistretto(commitment)))
+            },
+        ),
+    )?;
+    // Whether `proof` proves that the value in `commitment` is less than `2^bits`.
+    range_proof.set(
+        "verify",
+        LuaFunction::wrap(
+            |_lua, (proof, commitment, bits): (LuaRangeProof, LuaRistretto, usize)| {
+                let bits = range_proof_bits(bits)?;
+                charge_mana(RANGE_PROOF_MANA_PER_BIT.saturating_mul(bits as u64))?;
+                // The randomness used here only speeds up checking, and doesn't change the result.
+                Ok(proof
+                    .0
+                    .verify_single(
+                        bulletproof_gens(),
+                        &PedersenGens::default(),
+                        &mut range_proof_transcript(),
+                        &commitment.0.compress(),
+                        bits,
+                    )
+                    .is_ok())
+            },
+        ),
+    )?;
+    set_encoding_functions::<LuaRangeProof>(&range_proof)?;
+    signing_key.set(
+        "random",
+        LuaFunction::wrap(|_lua, r: LuaAnyUserData| {
+            let mut key = [0; 32];
+            r.borrow_mut::<LuaRng>()?.0.fill_bytes(&mut key);
+            Ok(LuaSigningKey(SigningKey::from_bytes(&key)))
+        }),
+    )?;
+    // The key whose secret is `bytes`. Keys can't be turned back into bytes.
+    signing_key.set(
+        "from_bytes",
+        LuaFunction::wrap(|_lua, bytes: LuaBytes| {
+            Ok(LuaSigningKey(SigningKey::from_bytes(&exact(
+                &bytes.0,
+                "A signing key",
+            )?)))
+        }),
+    )?;
+    set_encoding_functions::<LuaPublicKey>(&public_key)?;
+    set_encoding_functions::<LuaSignature>(&signature)?;
+    // The canonical encoding of plain data, see `value`.
+    crypto.set(
+        "encode",
+        LuaFunction::wrap(|_lua, value: LuaValue| {
+            let encoding = value_for_hashing(value)?.encode();
+            Ok(LuaBytes(encoding))
+        }),
+    )?;
+    // The id of the canonical encoding of `value`, hashed like `crypto.U256.hash`.
+    crypto.set(
+        "hash_value",
+        LuaFunction::wrap(|_lua, (value, algorithm): (LuaValue, Option<String>)| {
+            let algorithm = algorithm_named(algorithm)?;
+            let encoding = value_for_hashing(value)?.encode();
+            Ok(LuaU256(hash::hash_with(algorithm, &encoding)))
+        }),
+    )?;
+    crypto.set(
+        "decode",
+        LuaFunction::wrap(|lua, bytes: LuaBytes| {
+            charge_hashing(bytes.0.len())?;
+            Value::decode(&bytes.0)
+                .map_err(|x| x.to_string().into_lua_err())?
+                .into_lua(lua)
+        }),
+    )?;
+    signature.set(
+        "sign",
+        LuaFunction::wrap(|_lua, (sk, message): (LuaSigningKey, LuaBytes)| {
+            charge_mana(SIGN_MANA)?;
+            Ok(LuaSignature(sk.0.sign(&message.0)))
+        }),
+    )?;
+    signature.set(
+        "verify",
+        LuaFunction::wrap(
+            |_lua, (pk, message, signature): (LuaPublicKey, LuaBytes, LuaSignature)| {
+                charge_mana(VERIFY_MANA)?;
+                Ok(pk.0.verify_strict(&message.0, &signature.0).is_ok())
+            },
+        ),
+    )?;
+    // Whether every `signatures[i]` is a valid signature of `messages[i]` by `public_keys[i]`.
+    signature.set(
+        "verify_batch",
+        LuaFunction::wrap(
+            |_lua,
+             (public_keys, messages, signatures): (
+                Vec<LuaPublicKey>,
+                Vec<LuaBytes>,
+                Vec<LuaSignature>,
+            )| {
+                if public_keys.len() != messages.len() || messages.len() != signatures.len() {
+                    return Err(
+                        "Expected as many public keys, messages and signatures".into_lua_err()
+                    );
+                }
+                charge_mana(BATCH_VERIFY_MANA_PER_SIGNATURE * signatures.len() as u64)?;
+                let public_keys: Vec<_> = public_keys.into_iter().map(|x| x.0).collect();
+                let signatures: Vec<_> = signatures.into_iter().map(|x| x.0).collect();
+                let messages: Vec<_> = messages.iter().map(|x| x.0.as_slice()).collect();
+                Ok(signatures.iter().zip(&public_keys).all(|(signature, pk)| {
+                    is_torsion_free(pk.as_bytes()) && is_torsion_free(signature.r_bytes())
+                }) && ed25519_dalek::verify_batch(&messages, &signatures, &public_keys).is_ok())
+            },
+        ),
+    )?;
+    crypto.into_lua(lua)
+}
+
+/// The key and output length in `options`, checked against the `max_len` of the
+/// hash function, which is also the default length and the longest key.
+/// Charges the mana for hashing `data`.
+fn hash_options(
+    options: Option<LuaTable>,
+    data: &LuaBytes,
+    max_len: usize,
+) -> LuaResult<(Vec<u8>, usize)> {
+    let (key, len) = match options {
+        Some(options) => (
+            options.get::<_, Option<LuaBytes>>("key")?,
+            options.get::<_, Option<usize>>("len")?,
+        ),
+        None => (None, None),
+    };
+    let key = key.map(|x| x.0).unwrap_or_default();
+    let len = len.unwrap_or(max_len);
+    if key.len() > max_len {
+        return Err(format!("Keys can be at most {} bytes long", max_len).into_lua_err());
+    }
+    if len == 0 || len > max_len {
+        return Err(format!("Outputs are 1 to {} bytes long", max_len).into_lua_err());
+    }
+    charge_hashing(data.0.len())?;
+    Ok((key, len))
+}
+
+/// Charge the mana for hashing, encoding or decoding `len` bytes.
+fn charge_hashing(len: usize) -> LuaResult<()> {
+    charge_mana(HASH_MANA_PER_BLOCK.saturating_mul(len as u64 / 64 + 1))
+}
+
+/// `Value::from_lua`, charging what `charge_hashing` would for the encoding as it
+/// grows, so that a value that's big to encode runs out of mana while it's being
+/// converted rather than after.
+fn value_for_hashing(value: LuaValue) -> LuaResult<Value> {
+    charge_hashing(0)?;
+    let mut len = 0;
+    Value::from_lua_counting(value, &mut |grown| {
+        let blocks = len / 64;
+        len += grown;
+        charge_mana(HASH_MANA_PER_BLOCK.saturating_mul((len / 64 - blocks) as u64))
+    })
+}
+
+/// Whether `point` is a point with no small-order part.
+///
+/// Batches are checked with random coefficients, and with small-order parts in the
+/// keys or signatures, whether an invalid batch passes could depend on them. Those
+/// are rejected, so that every node gets the same result for the same batch.
+fn is_torsion_free(point: &[u8; 32]) -> bool {
+    CompressedEdwardsY(*point)
+        .decompress()
+        .is_some_and(|x| x.is_torsion_free())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HASH_MANA_PER_BLOCK;
+    use crate::script_vm::{mana_used, new_lua};
+    use mlua::prelude::*;
+
+    /// Run `code` with `crypto` and a seeded `rng` in scope.
+    fn run(code: &str) -> LuaResult<()> {
+        let lua = new_lua().unwrap();
+        lua.load(format!(
+            "local crypto = require('crypto')
+            local rng = crypto.Random.from_seed(string.rep('x', 32))
+            {}",
+            code
+        ))
+        .exec()
+    }
+
+    #[test]
+    fn malformed_input() {
+        let l = "edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010";
+        let p_plus_1 = format!("ee{}7f", "ff".repeat(30));
+        let cases = [
+            // Numbers that aren't whole, negative or too big.
+            "crypto.Scalar.from(-1)".to_string(),
+            "crypto.Scalar.from(1.5)".to_string(),
+            "crypto.Scalar.from(0/0)".to_string(),
+            "crypto.Scalar.from(2^64)".to_string(),
+            "crypto.U256.from(-0.5)".to_string(),
+            "crypto.U256.from(-2^53)".to_string(),
+            "crypto.U256.from(math.huge)".to_string(),
+            // Wrong lengths.
+            "crypto.Scalar.from(crypto.Bytes.from(string.rep('a', 33)))".to_string(),
+            "crypto.Scalar.from_bytes(crypto.Bytes.from(string.rep('a', 31)))".to_string(),
+            "crypto.U256.from_bytes(crypto.Bytes.from(string.rep('a', 33)))".to_string(),
+            "crypto.Point.deserialize('00')".to_string(),
+            "crypto.Ristretto.deserialize(string.rep('00', 33))".to_string(),
+            "crypto.PublicKey.deserialize(string.rep('00', 31))".to_string(),
+            "crypto.Signature.deserialize(string.rep('00', 63))".to_string(),
+            "crypto.RangeProof.deserialize(string.rep('00', 10))".to_string(),
+            // Not hex.
+            "crypto.U256.deserialize(string.rep('zz', 32))".to_string(),
+            "crypto.Bytes.deserialize('abc')".to_string(),
+            "crypto.U256.from_string('12x')".to_string(),
+            "crypto.U256.from_string('1', 37)".to_string(),
+            // Non-canonical scalars and invalid or non-canonical points.
+            format!("crypto.Scalar.deserialize('{}')", l),
+            format!("crypto.Scalar.deserialize('{}')", "ff".repeat(32)),
+            format!("crypto.Point.deserialize('{}')", p_plus_1),
+            format!("crypto.Point.deserialize('02{}')", "00".repeat(31)),
+            format!("crypto.Ristretto.deserialize('{}')", "ff".repeat(32)),
+            format!("crypto.PublicKey.deserialize('{}')", p_plus_1),
+            format!("crypto.Signature.deserialize('{}{}')", "00".repeat(32), l),
+            // Points aren't numbers.
+            "crypto.Scalar.from(crypto.Point.generator())".to_string(),
+        ];
+        for case in cases {
+            assert!(run(&case).is_err(), "{} didn't fail", case);
+        }
+        run(
+            "assert(crypto.U256.from(2^63) == crypto.U256.from_string('9223372036854775808'))
+            assert(crypto.Scalar.from(0) == crypto.Scalar.zero())
+            local g = crypto.Point.generator()
+            assert(crypto.Point.from_bytes(g:to_bytes()) == g)",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn signing_keys_dont_serialize() {
+        run("local sk = crypto.SigningKey.from_bytes(crypto.Bytes.from(string.rep('k', 32)))
+            assert(not pcall(function() return sk:to_bytes() end))
+            assert(not pcall(crypto.encode, {key = sk}))
+            assert(not require('lua/serpent').line(sk):find(crypto.Bytes.from(string.rep('k', 32)):hex()))
+            assert(crypto.encode(sk:public_key()))")
+        .unwrap();
+    }
+
+    #[test]
+    fn bytes_arguments() {
+        run("local sk = crypto.SigningKey.from_bytes(crypto.Bytes.from(string.rep('k', 32)))
+            local pk = sk:public_key()
+            local message = crypto.encode({a = 1})
+            local signature = crypto.Signature.sign(sk, message)
+            assert(signature == crypto.Signature.sign(sk, message:to_string()))
+            assert(crypto.Signature.verify(pk, message, signature))
+            assert(crypto.Signature.verify(pk, message:to_string(), signature))
+            assert(crypto.Signature.verify_batch({pk, pk}, {message, message:to_string()}, {signature, signature}))
+            assert(crypto.Ristretto.hash(message) == crypto.Ristretto.hash(message:to_string()))
+            local seed = crypto.Bytes.from(string.rep('s', 32))
+            assert(crypto.Scalar.random(crypto.Random.from_seed(seed))
+                == crypto.Scalar.random(crypto.Random.from_seed(seed:to_string())))")
+        .unwrap();
+    }
+
+    #[test]
+    fn hashing_charges_mana() {
+        let lua = new_lua().unwrap();
+        let setup = "local crypto = require('crypto')
+            local data = string.rep('a', 64000)
+            local encoding = crypto.encode(data)";
+        for code in [
+            "crypto.U256.hash(data)",
+            "crypto.Ristretto.hash(data)",
+            "crypto.hash.sha256(data)",
+            "crypto.hash_value(data)",
+            "crypto.encode(data)",
+            "crypto.decode(encoding)",
+        ] {
+            let f: LuaFunction = lua
+                .load(format!("{}\nreturn function() {} end", setup, code))
+                .eval()
+                .unwrap();
+            let before = mana_used();
+            f.call::<_, ()>(()).unwrap();
+            let used = mana_used() - before;
+            assert!(used >= 1000 * HASH_MANA_PER_BLOCK, "{} used {}", code, used);
+        }
+    }
+
+    /// `U256` with `max`, 0, 1, 2 and 2^255 in scope.
+    fn run_u256(code: &str) -> LuaResult<()> {
+        run(&format!(
+            "local U = crypto.U256
+            local max, zero, one, two = U.max, U.from(0), U.from(1), U.from(2)
+            local half = one:shl(255)
+            {}",
+            code
+        ))
+    }
+
+    #[test]
+    fn u256_overflow() {
+        // The operators fail.
+        for code in [
+            "max + 1",
+            "zero - 1",
+            "half * 2",
+            "one / 0",
+            "one % 0",
+            "two ^ 256",
+            "-one",
+        ] {
+            let code = format!("local x = {}", code);
+            assert!(run_u256(&code).is_err(), "{} didn't fail", code);
+        }
+        run_u256(
+            "assert(two ^ 255 == half and -zero == zero)
+            assert(U.from(7) / 2 == U.from(3) and U.from(7) % 3 == one)
+            -- The checked methods return nil.
+            assert(max:checked_add(1) == nil and max:checked_add(0) == max)
+            assert(zero:checked_sub(1) == nil and one:checked_sub(1) == zero)
+            assert(half:checked_mul(2) == nil and half:checked_mul(1) == half)
+            assert(one:checked_div(0) == nil and one:checked_mod(0) == nil)
+            assert(two:checked_pow(256) == nil and two:checked_pow(255) == half)
+            -- The wrapping methods wrap around 2^256.
+            assert(max:wrapping_add(1) == zero)
+            assert(zero:wrapping_sub(1) == max)
+            assert(half:wrapping_mul(2) == zero)
+            assert(two:wrapping_pow(256) == zero and two:wrapping_pow(255) == half)
+            assert(one:wrapping_neg() == max and zero:wrapping_neg() == zero)
+            -- The saturating methods stop at 0 and max.
+            assert(max:saturating_add(1) == max)
+            assert(zero:saturating_sub(1) == zero)
+            assert(half:saturating_mul(2) == max)
+            assert(two:saturating_pow(256) == max and two:saturating_pow(255) == half)
+            -- Shifting by 256 bits or more gives 0.
+            assert(max:shl(255) == half and one:shl(256) == zero and max:shl(1000) == zero)
+            assert(half:shr(255) == one and max:shr(256) == zero)
+            assert(max:band(half) == half and zero:bor(half):bxor(max) == half:bnot())",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn u256_strings() {
+        run_u256(
+            "for _, x in ipairs({zero, one, two, half, max, U.from(123456789)}) do
+                for _, base in ipairs({2, 10, 16, 36}) do
+                    assert(U.from_string(x:to_string(base), base) == x)
+                end
+                assert(U.from_string(x:to_string()) == x)
+            end
+            assert(max:to_string(16) == string.rep('f', 64))
+            assert(half:to_string(2) == '1' .. string.rep('0', 255))
+            assert(zero:to_string(36) == '0' and U.from(35):to_string(36) == 'z')
+            assert(max:to_string() ==
+                '115792089237316195423570985008687907853269984665640564039457584007913129639935')
+            assert(U.from_string('0xff') == U.from(255) and U.from_string('0b101') == U.from(5))",
+        )
+        .unwrap();
+        for code in [
+            "U.from_string('115792089237316195423570985008687907853269984665640564039457584007913129639936')",
+            "U.from_string(string.rep('f', 65), 16)",
+            "U.from_string('2', 2)",
+            "U.from_string('')",
+            "U.from_string('-1')",
+            "one:to_string(1)",
+            "one:to_string(37)",
+        ] {
+            assert!(run_u256(code).is_err(), "{} didn't fail", code);
+        }
+    }
+
+    #[test]
+    fn range_proof_sizes() {
+        run("local blinding = crypto.Scalar.random(rng)
+            local proof, commitment = crypto.RangeProof.prove(200, blinding, 8, rng)
+            assert(crypto.RangeProof.verify(proof, commitment, 8))
+            assert(not crypto.RangeProof.verify(proof, commitment, 16))")
+        .unwrap();
+        for bits in ["0", "7", "65", "2^40", "2^62"] {
+            let prove = format!(
+                "crypto.RangeProof.prove(1, crypto.Scalar.random(rng), {}, rng)",
+                bits
+            );
+            assert!(run(&prove).is_err(), "proved {} bits", bits);
+            let verify = format!(
+                "local proof, commitment = crypto.RangeProof.prove(1, crypto.Scalar.random(rng), 8, rng)
+                crypto.RangeProof.verify(proof, commitment, {})",
+                bits
+            );
+            assert!(run(&verify).is_err(), "verified {} bits", bits);
+        }
+    }
 }
@@ -1,17 +1,26 @@
 #![feature(btree_cursors)]
 
-use std::sync::{Arc, Mutex};
-
-use mlua::{Function, IntoLua, Lua, Value};
+use std::sync::Arc;
 
 use rand::SeedableRng;
+use tracing_subscriber::EnvFilter;
 
-use crate::{lua_curve25519::make_lib, node::Node, script_vm::NodeLock};
+use crate::{
+    node::Node,
+    script_vm::{new_lua, NodeLock},
+};
+pub mod block;
+pub mod dag;
 pub mod dht;
+pub mod hash;
 pub mod lua_curve25519;
+pub mod metrics;
 pub mod node;
 pub mod script_vm;
+pub mod sim;
+pub mod trace;
 pub mod types;
+pub mod value;
 
 #[derive(clap::Parser, Debug)]
 #[command(
@@ -21,26 +30,87 @@ pub mod types;
     long_about = None)]
 pub struct Cli {
     script: Option<String>,
+    /// Number of in-process executing nodes to start and bootstrap from.
+    #[arg(long, default_value_t = 0)]
+    seeds: usize,
+    /// Instead of running a script, simulate a network with this many DHT peers and report on lookups in it.
+    #[arg(long)]
+    simulate: Option<usize>,
+    /// Seed for `--simulate`. The same seed always gives the same simulation.
+    #[arg(long, default_value_t = 0)]
+    sim_seed: u64,
+    /// How many of the simulated peers can't accept inbound traffic, like peers behind a NAT.
+    #[arg(long, default_value_t = 0)]
+    sim_unreachable: usize,
+    /// Don't have the unreachable simulated peers use relays.
+    #[arg(long)]
+    sim_no_relays: bool,
+    /// Fetch the blocks that a block might call before running it.
+    #[arg(long)]
+    prefetch: bool,
+    /// Trace every block that the script runs, and write the trace to this file as JSON.
+    #[arg(long)]
+    trace: Option<String>,
+    /// Which logs to show, like `info` or `kelili::dht=trace`. Overrides `RUST_LOG`.
+    #[arg(long)]
+    log: Option<String>,
+    /// Print the metrics collected while running to stderr, when done.
+    #[arg(long)]
+    metrics: bool,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     use clap::*;
     let cli = Cli::parse();
 
-    let lua = Lua::new();
+    let filter = match &cli.log {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+
+    if let Some(peers) = cli.simulate {
+        let config = sim::Config {
+            peers,
+            seed: cli.sim_seed,
+            unreachable: cli.sim_unreachable.min(peers.saturating_sub(1)),
+            relays: !cli.sim_no_relays,
+            ..Default::default()
+        };
+        let report = sim::runtime()?.block_on(sim::lookups(&config))?;
+        println!("{}", report);
+        if cli.metrics {
+            eprint!("{}", metrics::snapshot());
+        }
+        return Ok(());
+    }
 
-    let std = std::fs::read("lua/lib.lua")?;
-    let std: Function = lua.load(std).into_function()?;
-    lua.set_named_registry_value("kelili.stdlib", std)?;
-    lua.set_named_registry_value("kelili.state_cache", lua.create_table()?)?;
-    let f = mlua::prelude::LuaFunction::wrap(|l, m| make_lib(l, m)).into_lua(&lua)?;
-    let _v = lua.load_from_function::<Value>("crypto", f.as_function().unwrap().clone());
+    let lua = new_lua()?;
 
     let mut rng = rand_chacha::ChaCha12Rng::from_entropy();
 
-    let node = Node::new(&mut rng);
-    let node = NodeLock(Arc::new(Mutex::new(node)));
-    lua.globals().set("node", node)?;
+    // The DHT peers run on this runtime for as long as the script does.
+    let runtime = tokio::runtime::Runtime::new()?;
+    let _guard = runtime.enter();
+    let mut node = Node::new(&mut rng);
+    node.prefetch = cli.prefetch;
+    if cli.trace.is_some() {
+        node.trace = Some(trace::Trace::default());
+    }
+    let _seeds = runtime.block_on(async {
+        let seeds = Node::spawn_local_seeds(&mut rng, cli.seeds).await?;
+        if cli.seeds > 0 {
+            let request_seeds: Vec<_> = seeds.iter().map(|x| x.request_dht.info()).collect();
+            let node_seeds: Vec<_> = seeds.iter().map(|x| x.node_dht.info()).collect();
+            node.bootstrap(&request_seeds, &node_seeds).await?;
+        }
+        Ok::<_, Box<dyn std::error::Error>>(seeds)
+    })?;
+    let node = NodeLock(Arc::new(tokio::sync::Mutex::new(node)));
+    lua.globals().set("node", node.clone())?;
 
     let script = cli
         .script
@@ -50,5 +120,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let code = std::fs::read(script)?;
     lua.load(code).set_name(script).exec()?;
 
+    if let Some(path) = cli.trace {
+        if let Some(trace) = runtime.block_on(node.0.lock()).trace.take() {
+            std::fs::write(path, trace.to_json())?;
+        }
+    }
+    if cli.metrics {
+        eprint!("{}", metrics::snapshot());
+    }
+
     Ok(())
 }
@@ -0,0 +1,119 @@
+//! Counters and histograms of what this process has done.
+//!
+//! Metrics are global to the process, so they add up the work of every node and
+//! peer running in it. Names are dotted paths, like `blocks.executions`.
+use std::{collections::BTreeMap, fmt, sync::Mutex};
+
+use serde::Serialize;
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    counters: BTreeMap::new(),
+    histograms: BTreeMap::new(),
+});
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Metrics {
+    pub counters: BTreeMap<String, u64>,
+    pub histograms: BTreeMap<String, Histogram>,
+}
+
+/// Values sorted into power-of-two buckets.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Histogram {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    /// How many values were in each bucket. Bucket `i` holds the values that are
+    /// less than `2^i` and not in an earlier bucket.
+    pub buckets: Vec<u64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+            buckets: vec![0; 65],
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: u64) {
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buckets[(u64::BITS - value.leading_zeros()) as usize] += 1;
+    }
+    pub fn mean(&self) -> f64 {
+        self.sum as f64 / self.count.max(1) as f64
+    }
+    /// An upper bound for the value that a fraction `q` of the values are at most.
+    pub fn quantile(&self, q: f64) -> u64 {
+        let target = (self.count as f64 * q).ceil() as u64;
+        let mut seen = 0;
+        for (i, amount) in self.buckets.iter().enumerate() {
+            seen += amount;
+            if seen >= target.max(1) {
+                let bound = 1u64.checked_shl(i as u32).map_or(u64::MAX, |x| x - 1);
+                return bound.min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Add `amount` to the counter `name`.
+pub fn count(name: &str, amount: u64) {
+    let mut metrics = METRICS.lock().unwrap();
+    match metrics.counters.get_mut(name) {
+        Some(counter) => *counter += amount,
+        None => {
+            metrics.counters.insert(name.to_string(), amount);
+        }
+    }
+}
+
+/// Add `value` to the histogram `name`.
+pub fn observe(name: &str, value: u64) {
+    let mut metrics = METRICS.lock().unwrap();
+    match metrics.histograms.get_mut(name) {
+        Some(histogram) => histogram.observe(value),
+        None => {
+            let mut histogram = Histogram::default();
+            histogram.observe(value);
+            metrics.histograms.insert(name.to_string(), histogram);
+        }
+    }
+}
+
+/// A copy of the metrics as they are now.
+pub fn snapshot() -> Metrics {
+    METRICS.lock().unwrap().clone()
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.counters {
+            writeln!(f, "{} {}", name, value)?;
+        }
+        for (name, histogram) in &self.histograms {
+            writeln!(
+                f,
+                "{} count={} mean={:.1} min={} p50<={} p99<={} max={}",
+                name,
+                histogram.count,
+                histogram.mean(),
+                histogram.min,
+                histogram.quantile(0.5),
+                histogram.quantile(0.99),
+                histogram.max
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,74 @@
-use std::error::Error;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    sync::{Arc, Mutex},
+};
 
+use futures::future::join_all;
 use mlua::prelude::*;
+use tracing::{debug, info, info_span, Instrument};
 
-use crate::{dht::Peer, lua_curve25519::LuaU256};
+use crate::{
+    block::Block,
+    dag,
+    dht::{self, ExecuteJob, Peer, PeerHandle, PeerInfo},
+    hash,
+    lua_curve25519::LuaU256,
+    metrics,
+    script_vm::{
+        charge_mana, mana_used, new_lua, out_of_mana, restart_mana_count, Budget, InBlock, Limits,
+    },
+    trace::{self, Divergence, Event, Trace},
+    value::Value,
+};
 
 use super::types::Id;
 
-pub type Code = Box<[u8]>;
-
-#[derive(serde::Serialize, serde::Deserialize)]
-pub struct Block {
-    pub index: u64,
-    pub mana_limit: u64,
-    pub memo_limit: u64,
-    pub code: Code,
-    pub name: Option<String>,
+/// A node is part of two overlays.
+///
+/// `request_dht` is the content store, where blocks are kept by their hash.
+/// `node_dht` is made of the nodes that execute blocks. They announce the
+/// results of the blocks they've run there, keyed by block hash, so that other
+/// nodes can ask for a result instead of running the block themselves.
+#[derive(Clone)]
+pub struct Node {
+    pub request_dht: PeerHandle,
+    pub node_dht: PeerHandle,
+    /// Whether to fetch the dependencies of a block before running it, see `prefetch`.
+    pub prefetch: bool,
+    /// Most mana and memory that a block run for another node can use, whatever
+    /// limits that node asks for. 0 means no limit.
+    pub max_remote_mana: u64,
+    pub max_remote_memo: u64,
+    /// Blocks that have been fetched already. Blocks never change, so these never get stale.
+    blocks: Arc<Mutex<HashMap<Id, Block>>>,
+    /// How many times a block that was going to run couldn't be fetched. Whether that
+    /// happens depends on the network rather than on the blocks, so runs during which
+    /// this goes up aren't cached or announced.
+    missing_blocks: u64,
+    /// Where the blocks that run are traced, if they are. Traced runs aren't taken
+    /// from the cache, so that the same block always gives the same trace.
+    pub trace: Option<Trace>,
 }
 
-pub struct Node {
-    pub request_dht: crate::dht::Peer,
-    pub node_dht: crate::dht::Peer,
+/// How many levels of dependencies `Node::prefetch` follows.
+pub const PREFETCH_DEPTH: usize = 16;
+/// Most blocks that `Node::prefetch` fetches before running one block.
+pub const PREFETCH_LIMIT: usize = 256;
+/// Default `Node::max_remote_mana`, about a second of running VM instructions.
+pub const MAX_REMOTE_MANA: u64 = 100_000_000;
+/// Default `Node::max_remote_memo`, in bytes.
+pub const MAX_REMOTE_MEMO: u64 = 64 << 20;
+
+/// How to check a result that another node computed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verify {
+    /// Trust the first node that answers.
+    None,
+    /// Run the block locally too, and check that the results are the same.
+    Reexecute,
+    /// Ask this many nodes, and only accept a result that most of them agree on.
+    Quorum(usize),
 }
 
 pub struct Context {
@@ -30,22 +79,251 @@ pub struct Context {
 
 use async_recursion::async_recursion;
 impl Node {
+    /// Create a node that doesn't know about any other peers.
+    ///
+    /// Until it's bootstrapped, the node works standalone: blocks are stored and
+    /// found locally, which is enough for local development.
+    ///
+    /// The DHT peers are spawned as tasks, so this must be called from within a Tokio runtime.
     pub fn new(rng: &mut dyn rand::RngCore) -> Self {
+        Self::with_peers(Peer::new(rng).spawn(), Peer::new(rng).spawn())
+    }
+    pub fn with_peers(request_dht: PeerHandle, node_dht: PeerHandle) -> Self {
         Self {
-            request_dht: Peer::new(rng),
-            node_dht: Peer::new(rng),
+            request_dht,
+            node_dht,
+            prefetch: false,
+            max_remote_mana: MAX_REMOTE_MANA,
+            max_remote_memo: MAX_REMOTE_MEMO,
+            blocks: Arc::default(),
+            missing_blocks: 0,
+            trace: None,
+        }
+    }
+    /// Join the overlays that `request_seeds` and `node_seeds` belong to.
+    pub async fn bootstrap(
+        &self,
+        request_seeds: &[PeerInfo],
+        node_seeds: &[PeerInfo],
+    ) -> Result<(), Box<dyn Error>> {
+        self.request_dht.bootstrap(request_seeds).await?;
+        self.node_dht.bootstrap(node_seeds).await
+    }
+    /// Start `amount` executing nodes that know about each other, for other nodes to bootstrap from.
+    pub async fn spawn_local_seeds(
+        rng: &mut dyn rand::RngCore,
+        amount: usize,
+    ) -> Result<Vec<Node>, Box<dyn Error>> {
+        let request_seeds = dht::spawn_local_seeds(rng, amount).await?;
+        let node_seeds = dht::spawn_local_seeds(rng, amount).await?;
+        let mut seeds = Vec::new();
+        for (request_dht, node_dht) in request_seeds.into_iter().zip(node_seeds) {
+            let seed = Node::with_peers(request_dht, node_dht);
+            seed.start_executor().await?;
+            seeds.push(seed);
+        }
+        Ok(seeds)
+    }
+    /// Run the blocks that other nodes ask us to run.
+    ///
+    /// Blocks are run in a VM of their own, on a separate thread, one at a time.
+    /// Asking for no limits, or for more than `max_remote_mana` and `max_remote_memo`,
+    /// gets those instead, so that a block can't keep the thread to itself.
+    pub async fn start_executor(&self) -> Result<(), Box<dyn Error>> {
+        let (jobs, mut rx) = tokio::sync::mpsc::unbounded_channel::<ExecuteJob>();
+        let mut node = self.clone();
+        let runtime = tokio::runtime::Handle::current();
+        std::thread::spawn(move || {
+            let lua = match new_lua() {
+                Ok(lua) => lua,
+                Err(e) => {
+                    while let Some(job) = rx.blocking_recv() {
+                        let _ = job.reply.send(Err(e.to_string()));
+                    }
+                    return;
+                }
+            };
+            while let Some(job) = rx.blocking_recv() {
+                let _cap = Limits::enter(&lua, node.max_remote_mana, node.max_remote_memo);
+                let result = runtime.block_on(async {
+                    let value = node
+                        .run_block_with_limits(&lua, &job.hash, job.mana_limit, job.memo_limit)
+                        .await?;
+                    let value = Value::from_lua(value)?;
+                    Ok::<_, Box<dyn Error>>(bincode::serialize(&value)?.into_boxed_slice())
+                });
+                let _ = job.reply.send(result.map_err(|e| e.to_string()));
+            }
+        });
+        self.node_dht.set_executor(jobs).await
+    }
+    /// Tell the executing nodes close to `hash` that running it returned `value`.
+    ///
+    /// Only results made of plain data can be announced; returns whether this one was.
+    pub async fn announce_result(
+        &self,
+        hash: &Id,
+        value: LuaValue<'_>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let Ok(value) = Value::from_lua(value) else {
+            return Ok(false);
+        };
+        let value = bincode::serialize(&value)?.into_boxed_slice();
+        self.node_dht.announce(hash, value).await?;
+        Ok(true)
+    }
+    /// Ask the executing nodes close to `hash` for the results they've announced for it,
+    /// along with the id of each node that announced it.
+    ///
+    /// The results aren't verified in any way.
+    pub async fn find_results(&self, hash: &Id) -> Result<Vec<(Id, Value)>, Box<dyn Error>> {
+        let mut results = Vec::new();
+        for (provider, value) in self.node_dht.find_records(hash).await? {
+            // Skip records that aren't results.
+            if let Ok(value) = bincode::deserialize(&value) {
+                results.push((provider, value));
+            }
+        }
+        Ok(results)
+    }
+    /// The result for `hash` announced by the most executing nodes, if any announced one.
+    pub async fn find_result(&self, hash: &Id) -> Result<Option<Value>, Box<dyn Error>> {
+        let mut counts: Vec<(Value, usize)> = Vec::new();
+        for (_, value) in self.find_results(hash).await? {
+            match counts.iter_mut().find(|(x, _)| *x == value) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((value, 1)),
+            }
+        }
+        Ok(counts
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .map(|(value, _)| value))
+    }
+    /// Ask the executing nodes closest to `hash` to run it, instead of running it ourselves.
+    ///
+    /// Only results made of plain data can be sent back. `verify` says how much
+    /// the nodes that run the block are trusted.
+    pub async fn execute_remote(
+        &mut self,
+        lua: &Lua,
+        hash: &Id,
+        mana_limit: u64,
+        memo_limit: u64,
+        verify: Verify,
+    ) -> Result<Value, Box<dyn Error>> {
+        let amount = match verify {
+            Verify::Quorum(0) => return Err("A quorum needs at least one node".into()),
+            Verify::Quorum(amount) => amount,
+            // Ask one node at a time until one of them runs the block.
+            _ => 1,
+        };
+        metrics::count("blocks.remote_executions", 1);
+        let mut results = Vec::new();
+        let mut last_error = "No executing nodes are known".to_string();
+        for peers in self.node_dht.closest_peers(hash, 20).await?.chunks(amount) {
+            let executions = peers
+                .iter()
+                .map(|peer| self.node_dht.execute(peer, hash, mana_limit, memo_limit));
+            // A node that can't be reached or sends back garbage is just one less answer.
+            for (peer, result) in peers
+                .iter()
+                .zip(futures::future::join_all(executions).await)
+            {
+                let result = result
+                    .map_err(|e| e.to_string())
+                    .and_then(|x| x)
+                    .and_then(|x| bincode::deserialize::<Value>(&x).map_err(|e| e.to_string()));
+                match result {
+                    Ok(value) => results.push(value),
+                    Err(e) => {
+                        info!(hash = %trace::id(hash), peer = %trace::id(&peer.id()), error = %e, "Remote execution failed");
+                        last_error = e;
+                    }
+                }
+            }
+            if results.len() >= amount {
+                break;
+            }
         }
+        match verify {
+            Verify::None => results
+                .into_iter()
+                .next()
+                .ok_or_else(|| format!("No node could run the block: {}", last_error).into()),
+            Verify::Reexecute => {
+                let remote = results
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| format!("No node could run the block: {}", last_error))?;
+                let local = self
+                    .run_block_with_limits(lua, hash, mana_limit, memo_limit)
+                    .await?;
+                if Value::from_lua(local)? != remote {
+                    return Err("Remote result doesn't match the local one".into());
+                }
+                Ok(remote)
+            }
+            Verify::Quorum(amount) => {
+                let mut counts: Vec<(Value, usize)> = Vec::new();
+                for value in results {
+                    match counts.iter_mut().find(|(x, _)| *x == value) {
+                        Some((_, count)) => *count += 1,
+                        None => counts.push((value, 1)),
+                    }
+                }
+                counts
+                    .into_iter()
+                    .find(|(_, count)| *count * 2 > amount)
+                    .map(|(value, _)| value)
+                    .ok_or_else(|| "Not enough nodes agreed on a result".into())
+            }
+        }
+    }
+    /// Store a block in `request_dht`, split into chunks, and return its hash.
+    pub async fn put_block(&self, block: &Block) -> Result<Id, Box<dyn Error>> {
+        dag::store(&self.request_dht, hash::DEFAULT, &block.encode()).await
+    }
+    /// Fetch the chunks of a block from `request_dht` and put it back together.
+    pub async fn get_block(&self, hash: &Id) -> Result<Option<Block>, Box<dyn Error>> {
+        if let Some(block) = self.blocks.lock().unwrap().get(hash) {
+            metrics::count("blocks.fetch_cache_hits", 1);
+            return Ok(Some(block.clone()));
+        }
+        let Some(data) = dag::fetch(&self.request_dht, hash).await? else {
+            debug!(hash = %trace::id(hash), "Block not found");
+            return Ok(None);
+        };
+        metrics::count("blocks.fetched", 1);
+        let block = Block::decode(&data)?;
+        self.blocks.lock().unwrap().insert(*hash, block.clone());
+        Ok(Some(block))
     }
-    pub async fn get_block(&mut self, hash: &Id) -> Result<Option<Block>, Box<dyn Error>> {
-        Ok(self
-            .request_dht
-            .find(hash)
-            .await?
-            .map(|data: Box<[u8]>| -> Result<Block, Box<dyn Error>> {
-                let data = bincode::deserialize(&*data)?;
-                Ok(data)
-            })
-            .transpose()?)
+    /// Fetch the blocks that `block` might call, the blocks that those might call,
+    /// and so on, all the blocks at each level at once. They're kept so that
+    /// running `block` doesn't have to wait for them one by one.
+    ///
+    /// Returns how many blocks were found. Blocks that can't be fetched are skipped,
+    /// since the block might not actually call them.
+    pub async fn prefetch(&self, block: &Block) -> usize {
+        let mut seen = HashSet::new();
+        let mut level = dependencies_of(block);
+        let mut found = 0;
+        for _ in 0..PREFETCH_DEPTH {
+            level.retain(|x| seen.insert(*x));
+            level.truncate(PREFETCH_LIMIT.saturating_sub(found));
+            if level.is_empty() {
+                break;
+            }
+            let blocks = join_all(level.iter().map(|hash| self.get_block(hash))).await;
+            level = Vec::new();
+            for block in blocks.into_iter().filter_map(|x| x.ok().flatten()) {
+                found += 1;
+                level.extend(dependencies_of(&block));
+            }
+        }
+        metrics::observe("blocks.prefetched", found as u64);
+        found
     }
 
     #[async_recursion(?Send)]
@@ -68,15 +346,35 @@ impl Node {
             "call" => {
                 let cont = io.get::<&str, mlua::Function>("cont")?;
                 let hasht = io.get::<&str, LuaU256>("hash")?;
-                let _max_mana = io.get::<&str, u64>("max_mana")?;
-                let _max_memo = io.get::<&str, u64>("max_memo")?;
+                let max_mana = io.get::<&str, u64>("max_mana")?;
+                let max_memo = io.get::<&str, u64>("max_memo")?;
                 let hash = hasht.0;
-                let ret = self.run_block(lua, &hash).await?;
+                self.trace_event(|| Event::Call {
+                    hash: trace::id(&hash),
+                    max_mana,
+                    max_memo,
+                });
+                // The caller gets to handle the called block failing, e.g. by running out of mana.
+                let ret = match self
+                    .run_block_with_limits(lua, &hash, max_mana, max_memo)
+                    .await
+                {
+                    Ok(ret) => ret,
+                    Err(e) => lua
+                        .create_table_from([(
+                            "error",
+                            lua.create_table_from([("message", e.to_string())])?,
+                        )])?
+                        .into_lua(lua)?,
+                };
                 self.exec_io(lua, context, cont.call(ret)?).await
             }
             "mark" => {
                 let cont = io.get::<&str, mlua::Function>("cont")?;
-                let hash = context.block_hash.clone();
+                let hash = context.block_hash;
+                self.trace_event(|| Event::Mark {
+                    hash: trace::id(&hash),
+                });
                 let f = LuaFunction::wrap(move |lua, uv: LuaValue| {
                     let udata = lua.create_any_userdata(MarkedTerm { hash })?;
                     udata.set_user_value(uv)?;
@@ -89,60 +387,480 @@ impl Node {
                 let cont = io.get::<&str, mlua::Function>("cont")?;
                 let hash;
                 let uv: LuaValue;
+                let mut marked_by = None;
                 if let Some(marked) = match marked.clone() {
                     mlua::Value::UserData(x) => Some(x),
                     _ => None,
                 } {
                     uv = marked.user_value()?;
                     let marked: MarkedTerm = marked.take()?;
+                    marked_by = Some(trace::id(&marked.hash));
                     hash = LuaU256(marked.hash).into_lua(lua)?;
                 } else {
                     uv = marked.clone();
                     hash = LuaValue::Nil;
                 };
+                self.trace_event(|| Event::Open { marked_by });
                 self.exec_io(lua, context, cont.call((uv, hash))?).await
             }
             "done" => Ok(io.get::<&str, mlua::Value>("value")?),
+            "error" => match io.get::<&str, mlua::Value>("value")? {
+                // The VM running out of memory gets here as the error itself, see `make_resume`.
+                mlua::Value::Error(e) => Err(e.into()),
+                message => Err(String::from_lua(message, lua)?.into()),
+            },
             x => return Err(format!("Invalid type {:?}", x).into()),
         }
     }
+    fn trace_event(&mut self, event: impl FnOnce() -> Event) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(event());
+        }
+    }
+    /// Run a block again, tracing it, and find where its trace differs from `expected`.
+    /// Returns `None` if the block ran the same way.
+    pub async fn replay(
+        &mut self,
+        lua: &Lua,
+        hash: &Id,
+        expected: &Trace,
+    ) -> Result<Option<Divergence>, Box<dyn Error>> {
+        let outer = self.trace.replace(Trace::default());
+        // Failing is part of the trace, and gets compared like everything else.
+        let _ = self.run_block(lua, hash).await;
+        let found = std::mem::replace(&mut self.trace, outer).unwrap_or_default();
+        Ok(expected.divergence(&found))
+    }
     pub async fn run_block<'lua>(
         &mut self,
         lua: &'lua Lua,
         hash: &Id,
     ) -> Result<mlua::Value<'lua>, Box<dyn Error>> {
+        self.run_block_with_limits(lua, hash, 0, 0).await
+    }
+    /// Run a block, failing if it uses more than `mana_limit` mana or more than
+    /// `memo_limit` bytes of memory. A limit of 0 means no limit other than the block's own.
+    ///
+    /// How each run went is cached along with the budget it had, so that the block
+    /// isn't run again when the outcome is already known: a run that succeeded or
+    /// failed on its own is reused by calls that can afford what it used, and a run
+    /// that ran out of mana or memory makes calls with no more of it fail right away.
+    /// Reusing a run charges the mana it used, as if the block had run again.
+    /// Runs in which some block couldn't be fetched aren't cached, since they could
+    /// go differently once it can be.
+    pub async fn run_block_with_limits<'lua>(
+        &mut self,
+        lua: &'lua Lua,
+        hash: &Id,
+        mana_limit: u64,
+        memo_limit: u64,
+    ) -> Result<mlua::Value<'lua>, Box<dyn Error>> {
+        let block = match self.get_block(hash).await {
+            Ok(Some(block)) => block,
+            result => {
+                self.missing_blocks += 1;
+                return Err(result.err().unwrap_or_else(|| "Block not found".into()));
+            }
+        };
+        let limits = Limits::enter(lua, mana_limit, memo_limit);
+        let block_limits = Limits::enter(lua, block.mana_limit, block.memo_limit);
+        let budget = Budget::current(lua);
+        self.trace_event(|| Event::Start {
+            hash: trace::id(hash),
+            name: block.name.clone(),
+        });
+
         let cache: mlua::Table = lua.named_registry_value("kelili.state_cache")?;
-        let cache_key = hash.to_string().as_str().into_lua(lua)?;
-        let cached = cache.get(cache_key.clone())?;
-        if let LuaValue::Table(cached) = cached {
-            println!(
-                "Fetching {:?} from cache ",
-                cached.get::<&str, mlua::Value>("name")?.to_string()?
-            );
-            Ok(cached.get("value")?)
-        } else {
-            let block = self.get_block(hash).await?.unwrap();
-            let mut ctx = Context {
-                block_hash: hash.clone(),
-                remaining_mana: block.mana_limit,
-                remaining_memo: block.memo_limit,
+        let cache_key = hash.to_string();
+        let runs = match cache.get::<_, Option<LuaTable>>(cache_key.as_str())? {
+            Some(runs) => runs,
+            None => {
+                let runs = lua.create_table()?;
+                cache.set(cache_key.as_str(), runs.clone())?;
+                runs
+            }
+        };
+        let cached = match self.trace {
+            Some(_) => Vec::new(),
+            None => runs.clone().sequence_values::<LuaTable>().collect(),
+        };
+        for entry in cached {
+            let entry = entry?;
+            let run = entry.get::<_, LuaAnyUserData>("run")?;
+            let run = run.borrow::<CachedRun>()?;
+            if !run.applies_to(&budget) {
+                continue;
+            }
+            debug!(hash = %trace::id(hash), name = ?block.name, "Reusing a cached run");
+            metrics::count("blocks.cache_hits", 1);
+            // Reusing a run costs as much as running the block again would.
+            return match &run.outcome {
+                Outcome::Done => {
+                    charge_mana(run.used.mana)?;
+                    Ok(entry.get("value")?)
+                }
+                Outcome::Failed(message) | Outcome::OutOfMemo(message) => {
+                    charge_mana(run.used.mana)?;
+                    Err(message.clone().into())
+                }
+                // Running it again would use up all the mana we have, and go over.
+                Outcome::OutOfMana(message) => {
+                    let _ = charge_mana(budget.mana.saturating_add(1));
+                    Err(message.clone().into())
+                }
             };
-            let f: LuaFunction = lua.named_registry_value("kelili.stdlib").unwrap();
-            let _: () = f.call(())?;
-            let mut code = lua.load(&*block.code);
-            if let Some(ref name) = block.name {
-                code = code.set_name(name);
-            }
-            println!("Running {:?}", &block.name);
-            let val = self.exec_io(lua, &mut ctx, code.call(()).unwrap()).await?;
-            cache.set(
-                cache_key.clone(),
-                lua.create_table_from([
-                    ("name", block.name.into_lua(lua)?),
-                    ("value", val.clone().into_lua(lua)?),
-                ])?,
-            )?;
-            Ok(val)
         }
+
+        if self.prefetch {
+            self.prefetch(&block).await;
+        }
+        let (mana_before, memo_before) = (mana_used(), lua.used_memory());
+        let missing_before = self.missing_blocks;
+        let span = info_span!("block", hash = %trace::id(hash), name = ?block.name);
+        let result = self.run_code(lua, hash, &block).instrument(span).await;
+        let used = Budget {
+            mana: mana_used() - mana_before,
+            memo: lua.used_memory().saturating_sub(memo_before) as u64,
+        };
+        let outcome = match &result {
+            Ok(_) => Outcome::Done,
+            Err(e) if out_of_mana() => Outcome::OutOfMana(e.to_string()),
+            Err(e) if is_memory_error(e.as_ref()) => Outcome::OutOfMemo(e.to_string()),
+            Err(e) => Outcome::Failed(e.to_string()),
+        };
+        // Keeping track of the run isn't part of it, and would fail if the block
+        // used up its memory.
+        drop(block_limits);
+        drop(limits);
+        metrics::count("blocks.executions", 1);
+        metrics::observe("blocks.mana_used", used.mana);
+        metrics::observe("blocks.memo_used", used.memo);
+        match &outcome {
+            Outcome::Done => {}
+            Outcome::Failed(e) | Outcome::OutOfMana(e) | Outcome::OutOfMemo(e) => {
+                metrics::count("blocks.failures", 1);
+                info!(hash = %trace::id(hash), name = ?block.name, error = %e, "Block failed");
+            }
+        }
+        self.trace_event(|| match &result {
+            Ok(val) => Event::Done {
+                hash: trace::id(hash),
+                value: Value::from_lua(val.clone()).ok(),
+                mana_used: used.mana,
+                memo_used: used.memo,
+            },
+            Err(e) => Event::Failed {
+                hash: trace::id(hash),
+                error: e.to_string(),
+                mana_used: used.mana,
+                memo_used: used.memo,
+            },
+        });
+        if self.missing_blocks != missing_before {
+            debug!(hash = %trace::id(hash), name = ?block.name, "Not caching a run that was missing blocks");
+            return result;
+        }
+        let entry = lua.create_table()?;
+        entry.set(
+            "run",
+            lua.create_any_userdata(CachedRun {
+                outcome,
+                used,
+                budget,
+            })?,
+        )?;
+        if let Ok(val) = &result {
+            entry.set("value", val.clone())?;
+            // The result is just as good when it couldn't be announced.
+            if let Err(e) = self.announce_result(hash, val.clone()).await {
+                info!(hash = %trace::id(hash), error = %e, "Announcing the result failed");
+            }
+        }
+        runs.push(entry)?;
+        result
+    }
+    async fn run_code<'lua>(
+        &mut self,
+        lua: &'lua Lua,
+        hash: &Id,
+        block: &Block,
+    ) -> Result<mlua::Value<'lua>, Box<dyn Error>> {
+        let mut ctx = Context {
+            block_hash: *hash,
+            remaining_mana: block.mana_limit,
+            remaining_memo: block.memo_limit,
+        };
+        restart_mana_count(lua)?;
+        let _in_block = InBlock::enter();
+        let f: LuaFunction = lua.named_registry_value("kelili.stdlib").unwrap();
+        let resume: LuaFunction = lua.named_registry_value("kelili.resume")?;
+        let _: () = f.call(resume)?;
+        let mut code = lua.load(&*block.code);
+        if let Some(ref name) = block.name {
+            code = code.set_name(name);
+        }
+        info!("Running");
+        self.exec_io(lua, &mut ctx, code.call(())?).await
+    }
+}
+
+/// How a run of a block went.
+#[derive(Clone, Debug)]
+enum Outcome {
+    Done,
+    /// It failed for a reason other than its budget, which a bigger budget wouldn't fix.
+    Failed(String),
+    OutOfMana(String),
+    OutOfMemo(String),
+}
+
+/// A run of a block, as kept in "kelili.state_cache".
+struct CachedRun {
+    outcome: Outcome,
+    /// What the run used.
+    used: Budget,
+    /// What the run could have used.
+    budget: Budget,
+}
+
+impl CachedRun {
+    /// Whether running the block again with `budget` would go the same way.
+    fn applies_to(&self, budget: &Budget) -> bool {
+        match self.outcome {
+            Outcome::Done | Outcome::Failed(_) => budget.covers(&self.used),
+            Outcome::OutOfMana(_) => budget.mana <= self.budget.mana,
+            Outcome::OutOfMemo(_) => budget.memo <= self.budget.memo,
+        }
+    }
+}
+
+/// Whether `e` is the VM running out of memory, possibly inside a callback.
+fn is_memory_error(e: &(dyn Error + 'static)) -> bool {
+    match e.downcast_ref::<LuaError>() {
+        Some(LuaError::MemoryError(_)) => true,
+        Some(LuaError::CallbackError { cause, .. }) => is_memory_error(cause.as_ref()),
+        _ => false,
+    }
+}
+
+/// The blocks that `block` declares as dependencies, and the hashes that appear in
+/// its code as `U256`s in hex, the way `crypto.U256.deserialize` takes them.
+///
+/// Not all of those hashes are blocks, and the code might not call all of them.
+pub fn dependencies_of(block: &Block) -> Vec<Id> {
+    let mut dependencies = block.dependencies.clone();
+    let code = &block.code;
+    let mut start = 0;
+    while start < code.len() {
+        let len = code[start..]
+            .iter()
+            .take_while(|x| x.is_ascii_hexdigit())
+            .count();
+        if len == 64 {
+            let bytes: [u8; 32] = hex::decode(&code[start..start + len])
+                .unwrap()
+                .try_into()
+                .unwrap();
+            let id = Id::from_le_bytes(bytes);
+            if hash::algorithm_of(&id).is_some() && !dependencies.contains(&id) {
+                dependencies.push(id);
+            }
+        }
+        start += len.max(1);
+    }
+    dependencies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOP: &str = "return kelili.io_run_fun(function()
+        local x = 0
+        for i = 1, 100000 do x = x + i end
+        return x
+    end)";
+
+    async fn put(node: &Node, code: &str) -> Id {
+        let block = Block::new(code.as_bytes().into(), Some("test".to_string()));
+        node.put_block(&block).await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remote_runs_are_capped() {
+        let rng = &mut rand::thread_rng();
+        let mut executor = Node::new(rng);
+        executor.max_remote_mana = 100_000;
+        executor.start_executor().await.unwrap();
+        let mut node = Node::new(rng);
+        node.bootstrap(&[executor.request_dht.info()], &[executor.node_dht.info()])
+            .await
+            .unwrap();
+        let lua = new_lua().unwrap();
+        let hash = put(&node, "while true do end").await;
+        // Asking for no limits gets the executor's.
+        let error = node
+            .execute_remote(&lua, &hash, 0, 0, Verify::None)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Out of mana"), "{}", error);
+        let hash = put(&node, "return kelili.io_run_fun(function() return 1 end)").await;
+        let value = node
+            .execute_remote(&lua, &hash, u64::MAX, 0, Verify::None)
+            .await
+            .unwrap();
+        assert_eq!(value, Value::Integer(1));
+    }
+
+    #[tokio::test]
+    async fn huge_results_arent_announced() {
+        let lua = new_lua().unwrap();
+        let mut node = Node::new(&mut rand::thread_rng());
+        // Small in Lua, but 2^40 tables as plain data.
+        let hash = put(
+            &node,
+            "return kelili.io_run_fun(function()
+                local t = {}
+                for i = 1, 40 do t = {t, t} end
+                return t
+            end)",
+        )
+        .await;
+        let value = node.run_block(&lua, &hash).await.unwrap();
+        assert!(!node.announce_result(&hash, value).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn runs_missing_blocks_arent_cached() {
+        let lua = new_lua().unwrap();
+        let mut node = Node::new(&mut rand::thread_rng());
+        // Where the called block will be, once it's stored.
+        let called = Block::new(
+            "return kelili.io_run_fun(function() return {n = 2} end)"
+                .as_bytes()
+                .into(),
+            Some("test".to_string()),
+        );
+        let called_hash = Node::new(&mut rand::thread_rng())
+            .put_block(&called)
+            .await
+            .unwrap();
+        let hash = put(
+            &node,
+            &format!(
+                "return kelili.io_run_fun(function()
+                    return IO.call_bubble(crypto.U256.deserialize('{}')).n
+                end)",
+                trace::id(&called_hash)
+            ),
+        )
+        .await;
+        let value = node.run_block(&lua, &hash).await.unwrap();
+        assert!(Value::from_lua(value).unwrap() != Value::Integer(2));
+        assert!(node.find_results(&hash).await.unwrap().is_empty());
+        node.put_block(&called).await.unwrap();
+        let value = node.run_block(&lua, &hash).await.unwrap();
+        assert_eq!(Value::from_lua(value).unwrap(), Value::Integer(2));
+    }
+
+    #[tokio::test]
+    async fn traces_replay_from_json() {
+        let lua = new_lua().unwrap();
+        let mut node = Node::new(&mut rand::thread_rng());
+        let hash = put(
+            &node,
+            "return kelili.io_run_fun(function()
+                return {
+                    huge = math.huge, nan = 0/0, text = 'abc', binary = '\\255',
+                    bytes = crypto.Bytes.from('ab'), u = crypto.U256.from(7),
+                }
+            end)",
+        )
+        .await;
+        node.trace = Some(Trace::default());
+        node.run_block(&lua, &hash).await.unwrap();
+        let json = node.trace.take().unwrap().to_json();
+        assert!(json.contains("\"abc\""), "{}", json);
+        let trace = Trace::from_json(&json).unwrap();
+        assert_eq!(node.replay(&lua, &hash, &trace).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reused_runs_charge_mana() {
+        let lua = new_lua().unwrap();
+        let mut node = Node::new(&mut rand::thread_rng());
+        let hash = put(&node, LOOP).await;
+        let mut used = Vec::new();
+        for _ in 0..2 {
+            let before = mana_used();
+            node.run_block(&lua, &hash).await.unwrap();
+            used.push(mana_used() - before);
+        }
+        assert!(used[0] > 0);
+        assert_eq!(used[0], used[1]);
+    }
+
+    #[tokio::test]
+    async fn reused_out_of_mana_runs_fail() {
+        let lua = new_lua().unwrap();
+        let mut node = Node::new(&mut rand::thread_rng());
+        let hash = put(&node, LOOP).await;
+        let mut errors = Vec::new();
+        for _ in 0..2 {
+            let before = mana_used();
+            let error = node
+                .run_block_with_limits(&lua, &hash, 5000, 0)
+                .await
+                .unwrap_err();
+            assert!(mana_used() - before > 5000);
+            errors.push(error.to_string());
+        }
+        assert_eq!(errors[0], errors[1]);
+        // With more mana it gets to run.
+        node.run_block_with_limits(&lua, &hash, 1_000_000, 0)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn out_of_memo_runs_are_told_apart() {
+        let lua = new_lua().unwrap();
+        let mut node = Node::new(&mut rand::thread_rng());
+        let hash = put(
+            &node,
+            "return kelili.io_run_fun(function()
+                local t = {}
+                for i = 1, 1000000 do t[i] = i end
+                return #t
+            end)",
+        )
+        .await;
+        assert!(node
+            .run_block_with_limits(&lua, &hash, 0, 100_000)
+            .await
+            .is_err());
+        let cache: LuaTable = lua.named_registry_value("kelili.state_cache").unwrap();
+        let runs: LuaTable = cache.get(hash.to_string()).unwrap();
+        let entry: LuaTable = runs.get(1).unwrap();
+        let run: LuaAnyUserData = entry.get("run").unwrap();
+        assert!(matches!(
+            run.borrow::<CachedRun>().unwrap().outcome,
+            Outcome::OutOfMemo(_)
+        ));
+        // Saying so isn't enough.
+        let hash = put(
+            &node,
+            "return kelili.io_run_fun(function() error('not enough memory', 0) end)",
+        )
+        .await;
+        let error = node.run_block(&lua, &hash).await.unwrap_err();
+        assert_eq!(error.to_string(), "not enough memory");
+        let runs: LuaTable = cache.get(hash.to_string()).unwrap();
+        let entry: LuaTable = runs.get(1).unwrap();
+        let run: LuaAnyUserData = entry.get("run").unwrap();
+        assert!(matches!(
+            run.borrow::<CachedRun>().unwrap().outcome,
+            Outcome::Failed(_)
+        ));
     }
 }
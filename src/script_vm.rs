@@ -1,44 +1,384 @@
 use crate::{
-    lua_curve25519::LuaU256,
-    node::{Block, Node},
+    block::Block,
+    lua_curve25519::{make_lib, LuaU256},
+    metrics,
+    node::{Node, Verify},
+    trace::Trace,
 };
-use mlua::prelude::*;
-use std::sync::{Arc, Mutex};
-use tokio::runtime::Runtime;
+use ed25519_dalek::SigningKey;
+use mlua::{ffi, prelude::*};
+use std::{cell::Cell, error::Error, sync::Arc};
+use tokio::{runtime::Handle, sync::Mutex};
+
+/// How many VM instructions are run between two mana charges.
+pub const INSTRUCTIONS_PER_CHARGE: u64 = 1000;
+
+thread_local! {
+    /// Mana used so far by the VM running on this thread, and how much it may use in total.
+    static MANA: Cell<(u64, u64)> = const { Cell::new((0, u64::MAX)) };
+}
+
+thread_local! {
+    /// How many blocks are running on this thread, one inside another.
+    static BLOCKS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Whether the code running on this thread is part of a block, rather than an off-chain script.
+pub fn in_block() -> bool {
+    BLOCKS.get() > 0
+}
+
+/// Marks code as part of a block while this is alive.
+pub struct InBlock(());
+
+impl InBlock {
+    pub fn enter() -> Self {
+        BLOCKS.set(BLOCKS.get() + 1);
+        Self(())
+    }
+}
+
+impl Drop for InBlock {
+    fn drop(&mut self) {
+        BLOCKS.set(BLOCKS.get() - 1);
+    }
+}
+
+/// Use up `amount` mana, failing if that goes over the current limit.
+pub fn charge_mana(amount: u64) -> LuaResult<()> {
+    let (used, limit) = MANA.get();
+    let used = used.saturating_add(amount);
+    MANA.set((used, limit));
+    if used > limit {
+        Err("Out of mana".into_lua_err())
+    } else {
+        Ok(())
+    }
+}
+
+/// How much mana and memory the code running now can still use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Budget {
+    pub mana: u64,
+    pub memo: u64,
+}
+
+impl Budget {
+    pub fn current(lua: &Lua) -> Self {
+        let (used, limit) = MANA.get();
+        // Reading the memory limit means setting it.
+        let memo_limit = lua.set_memory_limit(0).unwrap_or(0);
+        let _ = lua.set_memory_limit(memo_limit);
+        Self {
+            mana: limit.saturating_sub(used),
+            memo: match memo_limit {
+                0 => u64::MAX,
+                x => x.saturating_sub(lua.used_memory()) as u64,
+            },
+        }
+    }
+    /// Whether everything `used` fits in this budget.
+    pub fn covers(&self, used: &Budget) -> bool {
+        used.mana <= self.mana && used.memo <= self.memo
+    }
+}
+
+/// Mana used so far by the VM running on this thread.
+pub fn mana_used() -> u64 {
+    MANA.get().0
+}
+
+/// Whether the code running on this thread has gone over its mana limit.
+pub fn out_of_mana() -> bool {
+    let (used, limit) = MANA.get();
+    used > limit
+}
+
+/// Mana and memo limits that apply while this is alive. A limit of 0 means no limit.
+///
+/// Limits nest: the effective limit is always the tightest one of the enclosing `Limits`.
+pub struct Limits<'lua> {
+    lua: &'lua Lua,
+    mana_limit: u64,
+    memo_limit: Option<usize>,
+}
+
+impl<'lua> Limits<'lua> {
+    pub fn enter(lua: &'lua Lua, mana: u64, memo: u64) -> Self {
+        let (used, mana_limit) = MANA.get();
+        if mana != 0 {
+            MANA.set((used, mana_limit.min(used.saturating_add(mana))));
+        }
+        let memo_limit = (memo != 0).then(|| {
+            let limit = lua.used_memory().saturating_add(memo as usize);
+            let previous = lua.set_memory_limit(limit).unwrap_or(0);
+            if previous != 0 && previous < limit {
+                let _ = lua.set_memory_limit(previous);
+            }
+            previous
+        });
+        Self {
+            lua,
+            mana_limit,
+            memo_limit,
+        }
+    }
+}
+
+impl Drop for Limits<'_> {
+    fn drop(&mut self) {
+        MANA.set((MANA.get().0, self.mana_limit));
+        if let Some(previous) = self.memo_limit {
+            let _ = self.lua.set_memory_limit(previous);
+        }
+    }
+}
+
+unsafe extern "C-unwind" fn mana_hook(state: *mut ffi::lua_State, _: *mut ffi::lua_Debug) {
+    if charge_mana(INSTRUCTIONS_PER_CHARGE).is_err() {
+        ffi::luaL_error(state, c"Out of mana".as_ptr());
+    }
+}
+
+unsafe extern "C-unwind" fn set_mana_hook(state: *mut ffi::lua_State) -> std::ffi::c_int {
+    // LuaJIT hooks apply to every coroutine, which is what we want since blocks run in them.
+    // `Lua::set_hook` only calls its hook for the thread it was set on, so it can't be used here.
+    ffi::lua_sethook(
+        state,
+        Some(mana_hook),
+        ffi::LUA_MASKCOUNT,
+        INSTRUCTIONS_PER_CHARGE as _,
+    );
+    0
+}
+
+/// Create a VM that can run blocks.
+///
+/// Mana is charged for the instructions it runs. The JIT is turned off, since
+/// instructions in compiled code can't be counted.
+pub fn new_lua() -> Result<Lua, Box<dyn Error>> {
+    let lua = Lua::new();
+
+    let std = std::fs::read("lua/lib.lua")?;
+    let std: LuaFunction = lua.load(std).set_name("lua/lib.lua").into_function()?;
+    lua.set_named_registry_value("kelili.stdlib", std)?;
+    lua.set_named_registry_value("kelili.resume", make_resume(&lua)?)?;
+    lua.set_named_registry_value("kelili.state_cache", lua.create_table()?)?;
+    lua.load_from_function::<LuaValue>("crypto", lua.create_function(make_lib)?)?;
+
+    lua.load("jit.off()").exec()?;
+    restart_mana_count(&lua)?;
+    Ok(lua)
+}
+
+/// `coroutine.resume`, but with the status `lua_resume` returned in front instead of
+/// whether it succeeded.
+unsafe extern "C-unwind" fn resume_with_status(state: *mut ffi::lua_State) -> std::ffi::c_int {
+    ffi::luaL_checktype(state, 1, ffi::LUA_TTHREAD);
+    let co = ffi::lua_tothread(state, 1);
+    let nargs = ffi::lua_gettop(state) - 1;
+    ffi::luaL_checkstack(co, nargs, std::ptr::null());
+    ffi::lua_xmove(state, co, nargs);
+    let mut nresults = 0;
+    let status = ffi::lua_resume(co, state, nargs, &mut nresults);
+    if status != ffi::LUA_OK && status != ffi::LUA_YIELD {
+        // Just the error is left.
+        nresults = 1;
+    }
+    ffi::luaL_checkstack(state, nresults + 1, std::ptr::null());
+    ffi::lua_pushinteger(state, status as _);
+    ffi::lua_xmove(co, state, nresults);
+    nresults + 1
+}
+
+/// A `coroutine.resume` that gives errors from the VM running out of memory as a
+/// `LuaError::MemoryError`, which the coroutine itself can't make, rather than as
+/// their message, so that they can't be confused with a block raising an error
+/// that says the same.
+fn make_resume(lua: &Lua) -> LuaResult<LuaFunction<'_>> {
+    let resume_with_status =
+        lua.create_registry_value(unsafe { lua.create_c_function(resume_with_status)? })?;
+    lua.create_function(move |lua, args: LuaMultiValue| {
+        let f: LuaFunction = lua.registry_value(&resume_with_status)?;
+        let mut values = f.call::<_, LuaMultiValue>(args)?;
+        let status = values.pop_front().and_then(|x| x.as_i32());
+        match status {
+            Some(ffi::LUA_OK | ffi::LUA_YIELD) => values.push_front(LuaValue::Boolean(true)),
+            Some(ffi::LUA_ERRMEM) => {
+                let message = values.pop_front().unwrap_or(LuaValue::Nil).to_string()?;
+                values.push_front(LuaValue::Error(LuaError::MemoryError(message)));
+                values.push_front(LuaValue::Boolean(false));
+            }
+            _ => values.push_front(LuaValue::Boolean(false)),
+        }
+        Ok(values)
+    })
+}
+
+/// Start counting the instructions until the next mana charge from 0, so that
+/// running the same code always charges the same amount of mana.
+pub fn restart_mana_count(lua: &Lua) -> LuaResult<()> {
+    unsafe { lua.create_c_function(set_mana_hook)? }.call(())
+}
 
 #[derive(FromLua, Clone)]
 pub struct NodeLock(pub Arc<Mutex<Node>>);
-
 impl mlua::UserData for NodeLock {
     fn add_methods<'outer, M: LuaUserDataMethods<'outer, Self>>(methods: &mut M) {
         methods.add_method(
             "new_block",
-            |_lua, node, (code, name): (bstr::BString, Option<String>)| {
-                let h = Runtime::new().unwrap().block_on(async {
-                    let mut node = node.0.lock().unwrap();
-                    let block = Block {
-                        index: 0,
-                        mana_limit: 0,
-                        memo_limit: 0,
-                        code: code.to_vec().into_boxed_slice(),
-                        name,
-                    };
-                    let q = bincode::serialize(&block).unwrap().into_boxed_slice();
-                    let h = node.request_dht.hash(&q);
-                    node.request_dht.store(q).await.unwrap();
-                    return LuaU256(h);
+            // `meta` can have a `timestamp`, a list of `dependencies`, and a 32-byte
+            // Ed25519 `signing_key` to sign the block with.
+            |_lua,
+             node,
+             (code, name, index, meta): (
+                bstr::BString,
+                Option<String>,
+                Option<u64>,
+                Option<LuaTable>,
+            )| {
+                let mut block = Block::new(code.to_vec().into_boxed_slice(), name);
+                block.index = index.unwrap_or(0);
+                if let Some(meta) = meta {
+                    block.timestamp = meta.get("timestamp")?;
+                    if let Some(dependencies) =
+                        meta.get::<_, Option<Vec<LuaU256>>>("dependencies")?
+                    {
+                        block.dependencies = dependencies.into_iter().map(|x| x.0).collect();
+                    }
+                    if let Some(key) = meta.get::<_, Option<bstr::BString>>("signing_key")? {
+                        let key: [u8; 32] = key
+                            .as_slice()
+                            .try_into()
+                            .map_err(|_| "Signing keys are 32 bytes long".into_lua_err())?;
+                        block.sign(&SigningKey::from_bytes(&key));
+                    }
+                }
+                let h = Handle::current().block_on(async {
+                    let node = node.0.lock().await;
+                    node.put_block(&block).await
                 });
-                Ok(h)
+                Ok(LuaU256(h.map_err(|x| x.to_string().into_lua_err())?))
             },
         );
+        // Everything about a block other than its code, or nil if it can't be found.
+        methods.add_method("block_info", |lua, node, hasht: LuaU256| {
+            let block = Handle::current().block_on(async {
+                let node = node.0.lock().await;
+                node.get_block(&hasht.0).await
+            });
+            let Some(block) = block.map_err(|x| x.to_string().into_lua_err())? else {
+                return Ok(LuaValue::Nil);
+            };
+            let info = lua.create_table()?;
+            info.set("name", block.name)?;
+            info.set("index", block.index)?;
+            info.set("mana_limit", block.mana_limit)?;
+            info.set("memo_limit", block.memo_limit)?;
+            info.set("timestamp", block.timestamp)?;
+            let dependencies: Vec<_> = block.dependencies.into_iter().map(LuaU256).collect();
+            info.set("dependencies", dependencies)?;
+            if let Some(author) = block.author {
+                info.set("author", hex::encode(author.public_key.as_bytes()))?;
+            }
+            info.into_lua(lua)
+        });
+        // The metrics collected so far in this process, as a table with `counters`
+        // and `histograms`, see `metrics`.
+        methods.add_method("metrics", |lua, _node, ()| {
+            let metrics = metrics::snapshot();
+            let histograms = lua.create_table()?;
+            for (name, histogram) in metrics.histograms {
+                let t = lua.create_table()?;
+                t.set("count", histogram.count)?;
+                t.set("sum", histogram.sum)?;
+                t.set("min", histogram.min)?;
+                t.set("max", histogram.max)?;
+                t.set("mean", histogram.mean())?;
+                t.set("p50", histogram.quantile(0.5))?;
+                t.set("p99", histogram.quantile(0.99))?;
+                histograms.set(name, t)?;
+            }
+            let t = lua.create_table()?;
+            t.set("counters", metrics.counters)?;
+            t.set("histograms", histograms)?;
+            Ok(t)
+        });
+        // Start tracing the blocks that run, see `trace`.
+        methods.add_method("start_trace", |_lua, node, ()| {
+            Handle::current().block_on(node.0.lock()).trace = Some(Trace::default());
+            Ok(())
+        });
+        // Stop tracing, and return the trace as JSON, or nil if there wasn't one.
+        methods.add_method("stop_trace", |_lua, node, ()| {
+            let trace = Handle::current().block_on(node.0.lock()).trace.take();
+            Ok(trace.map(|x| x.to_json()))
+        });
+        // Run a block again and compare it against a trace in JSON.
+        // Returns nil if it ran the same way, or where it went differently.
+        methods.add_method("replay", |lua, node, (hasht, json): (LuaU256, String)| {
+            let expected = Trace::from_json(&json).map_err(|x| x.into_lua_err())?;
+            let ret = Handle::current().block_on(async {
+                let mut node = node.0.lock().await;
+                node.replay(lua, &hasht.0, &expected).await
+            });
+            Ok(ret
+                .map_err(|x| x.to_string().into_lua_err())?
+                .map(|x| x.to_string()))
+        });
+        methods.add_method("find_result", |lua, node, hasht: LuaU256| {
+            let ret = Handle::current().block_on(async {
+                let node = node.0.lock().await;
+                node.find_result(&hasht.0).await
+            });
+            ret.map_err(|x| x.to_string().into_lua_err())?
+                .map(|x| x.into_lua(lua))
+                .transpose()
+        });
         methods.add_method(
             "run_block",
             |lua, node, (hasht, _param): (LuaU256, mlua::Value)| {
-                let ret = Runtime::new().unwrap().block_on(async {
-                    let mut node = node.0.lock().unwrap();
-                    node.run_block(lua, &hasht.0).await.unwrap()
+                let ret = Handle::current().block_on(async {
+                    let mut node = node.0.lock().await;
+                    node.run_block(lua, &hasht.0).await
+                });
+                ret.map_err(|x| x.to_string().into_lua_err())
+            },
+        );
+        // `verify` is nil, "reexecute", or the number of nodes to ask and compare.
+        methods.add_method(
+            "run_remote",
+            |lua,
+             node,
+             (hasht, max_mana, max_memo, verify): (
+                LuaU256,
+                Option<u64>,
+                Option<u64>,
+                LuaValue,
+            )| {
+                let verify = match verify {
+                    LuaValue::Nil => Verify::None,
+                    LuaValue::String(x) if x == "reexecute" => Verify::Reexecute,
+                    LuaValue::Integer(x) if x > 0 => Verify::Quorum(x as usize),
+                    x => {
+                        return Err(
+                            format!("Invalid verification {:?}", x.to_string()?).into_lua_err()
+                        )
+                    }
+                };
+                let ret = Handle::current().block_on(async {
+                    let mut node = node.0.lock().await;
+                    node.execute_remote(
+                        lua,
+                        &hasht.0,
+                        max_mana.unwrap_or(0),
+                        max_memo.unwrap_or(0),
+                        verify,
+                    )
+                    .await
                 });
-                Ok(ret)
+                ret.map_err(|x| x.to_string().into_lua_err())?
+                    .into_lua(lua)
             },
         );
     }
@@ -0,0 +1,392 @@
+//! Deterministic simulation of a network of DHT peers.
+//!
+//! All peers run on a single-threaded runtime whose clock is paused, so time only
+//! moves forward when every task is waiting on a timer. Messages between peers go
+//! through a router that decides whether each one gets lost and how long it takes
+//! to arrive. Given the same seed, a simulation sends and delivers the same
+//! messages in the same order, and ends up with the same digest.
+//!
+//! Some peers can be made unreachable, like peers behind a NAT: they only get
+//! messages from the peers that they've sent messages to in the last `CONNECTION_TIMEOUT`.
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use blake2::Digest;
+use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
+use tokio::{sync::mpsc, time::Instant};
+
+use crate::dht::{Id, Message, Peer, PeerHandle, QUEUE_SIZE};
+
+/// How long an unreachable peer can get messages from a peer after sending one to it.
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub peers: usize,
+    pub seed: u64,
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+    /// Probability of each message getting lost.
+    pub loss: f64,
+    /// How many peers can't accept inbound traffic. These are the last ones.
+    pub unreachable: usize,
+    /// Whether unreachable peers use a reachable peer as their relay.
+    pub relays: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            peers: 32,
+            seed: 0,
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(200),
+            loss: 0.0,
+            unreachable: 0,
+            relays: true,
+        }
+    }
+}
+
+/// A runtime that simulations can run on.
+pub fn runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    pub delivered: u64,
+    pub dropped: u64,
+    /// Hash of every routing decision made so far, with the time it was made.
+    pub digest: String,
+}
+
+struct Network {
+    rng: ChaCha12Rng,
+    /// Where the router delivers the messages for each peer.
+    inboxes: Vec<mpsc::Sender<Message>>,
+    index: HashMap<Id, usize>,
+    reachable: Vec<bool>,
+    /// When each unreachable peer last sent a message to each peer, which can answer it for a while.
+    connections: HashMap<(usize, usize), Instant>,
+    /// The group that each peer is in. Messages only go between peers in the same group.
+    groups: Option<Vec<usize>>,
+    min_latency: Duration,
+    max_latency: Duration,
+    loss: f64,
+    started_at: Instant,
+    delivered: u64,
+    dropped: u64,
+    digest: blake2::Blake2s256,
+}
+
+impl Network {
+    /// Decide what happens to `msg`, which was sent to peer `to`.
+    /// Returns how long it takes to arrive, or `None` if it gets lost.
+    fn route(&mut self, to: usize, msg: &Message) -> Option<Duration> {
+        let from = self.index.get(&msg.from().id()).copied();
+        let partitioned = match (&self.groups, from) {
+            (Some(groups), Some(from)) => groups[from] != groups[to],
+            _ => false,
+        };
+        if let Some(from) = from {
+            if !self.reachable[from] {
+                self.connections.insert((from, to), Instant::now());
+            }
+        }
+        let connected = from
+            .and_then(|from| self.connections.get(&(to, from)))
+            .is_some_and(|x| x.elapsed() < CONNECTION_TIMEOUT);
+        let unreachable = !self.reachable[to] && !connected;
+        let lost = partitioned || unreachable || self.rng.gen_bool(self.loss);
+        let latency = self.rng.gen_range(self.min_latency..=self.max_latency);
+        self.digest.update(format!(
+            "{:?} {:?} {} {} {:?}\n",
+            self.started_at.elapsed(),
+            from,
+            to,
+            lost,
+            msg.contents()
+        ));
+        if lost {
+            self.dropped += 1;
+            None
+        } else {
+            self.delivered += 1;
+            Some(latency)
+        }
+    }
+}
+
+pub struct Simulation {
+    pub peers: Vec<PeerHandle>,
+    /// For the scenario being simulated to make its own random choices.
+    pub rng: ChaCha12Rng,
+    network: Arc<Mutex<Network>>,
+}
+
+impl Simulation {
+    /// Start `config.peers` peers that don't know about each other yet.
+    ///
+    /// This must be called from within a runtime made with `runtime`.
+    pub fn new(config: &Config) -> Self {
+        let mut rng = ChaCha12Rng::seed_from_u64(config.seed);
+        let (routed_tx, mut routed_rx) = mpsc::unbounded_channel::<(usize, Message)>();
+        let mut peers = Vec::new();
+        let mut inboxes = Vec::new();
+        let mut index = HashMap::new();
+        for i in 0..config.peers {
+            // Peers tell others to send to `outbox`, which is read by the router.
+            let (inbox, rx) = mpsc::channel(QUEUE_SIZE);
+            let (outbox, mut outbox_rx) = mpsc::channel(QUEUE_SIZE);
+            let peer = Peer::with_channel(&mut rng, outbox, rx);
+            index.insert(peer.info().id(), i);
+            inboxes.push(inbox);
+            peers.push(peer.spawn());
+            let routed_tx = routed_tx.clone();
+            tokio::spawn(async move {
+                while let Some(msg) = outbox_rx.recv().await {
+                    if routed_tx.send((i, msg)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        let network = Arc::new(Mutex::new(Network {
+            rng: ChaCha12Rng::seed_from_u64(rng.next_u64()),
+            reachable: (0..config.peers)
+                .map(|i| i + config.unreachable < config.peers)
+                .collect(),
+            connections: HashMap::new(),
+            inboxes,
+            index,
+            groups: None,
+            min_latency: config.min_latency,
+            max_latency: config.max_latency,
+            loss: config.loss,
+            started_at: Instant::now(),
+            delivered: 0,
+            dropped: 0,
+            digest: blake2::Blake2s256::new(),
+        }));
+        let router = network.clone();
+        tokio::spawn(async move {
+            while let Some((to, msg)) = routed_rx.recv().await {
+                let mut network = router.lock().unwrap();
+                if let Some(latency) = network.route(to, &msg) {
+                    let inbox = network.inboxes[to].clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(latency).await;
+                        let _ = inbox.try_send(msg);
+                    });
+                }
+            }
+        });
+        Self {
+            peers,
+            rng,
+            network,
+        }
+    }
+    /// Split the network so that messages only go between peers in the same group.
+    /// Peers that aren't in any group are put together in a group of their own.
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let mut network = self.network.lock().unwrap();
+        let mut peer_groups = vec![groups.len(); network.inboxes.len()];
+        for (group, peers) in groups.iter().enumerate() {
+            for peer in peers.iter() {
+                peer_groups[*peer] = group;
+            }
+        }
+        network.groups = Some(peer_groups);
+    }
+    /// Undo `partition`.
+    pub fn heal(&self) {
+        self.network.lock().unwrap().groups = None;
+    }
+    pub fn set_loss(&self, loss: f64) {
+        self.network.lock().unwrap().loss = loss;
+    }
+    pub fn is_reachable(&self, peer: usize) -> bool {
+        self.network.lock().unwrap().reachable[peer]
+    }
+    pub fn stats(&self) -> Stats {
+        let network = self.network.lock().unwrap();
+        Stats {
+            delivered: network.delivered,
+            dropped: network.dropped,
+            digest: hex::encode(network.digest.clone().finalize()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LookupReport {
+    pub stored: usize,
+    pub found: usize,
+    pub found_while_partitioned: usize,
+    pub found_after_healing: usize,
+    pub stats: Stats,
+}
+
+impl std::fmt::Display for LookupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Stored {} values", self.stored)?;
+        writeln!(f, "Found {}/{}", self.found, self.stored)?;
+        writeln!(
+            f,
+            "Found {}/{} while partitioned in two",
+            self.found_while_partitioned, self.stored
+        )?;
+        writeln!(
+            f,
+            "Found {}/{} after healing",
+            self.found_after_healing, self.stored
+        )?;
+        writeln!(
+            f,
+            "Delivered {} messages, dropped {}",
+            self.stats.delivered, self.stats.dropped
+        )?;
+        write!(f, "Digest {}", self.stats.digest)
+    }
+}
+
+/// Bootstrap every peer from the first one, with the unreachable ones using a
+/// random reachable peer as their relay if `config.relays` is set. Then store a value from a random peer for
+/// each peer, and look each value up from another random peer, first with the
+/// whole network, then with it partitioned in two halves, then healed again.
+pub async fn lookups(config: &Config) -> Result<LookupReport, Box<dyn Error>> {
+    let mut sim = Simulation::new(config);
+    let reachable: Vec<_> = (0..sim.peers.len())
+        .filter(|x| sim.is_reachable(*x))
+        .collect();
+    for i in 0..sim.peers.len() {
+        if config.relays && !sim.is_reachable(i) && !reachable.is_empty() {
+            let relay = sim.peers[reachable[sim.rng.gen_range(0..reachable.len())]].info();
+            sim.peers[i].set_relay(&relay).await?;
+        }
+    }
+    let seeds = [sim.peers[0].info()];
+    for peer in &sim.peers[1..] {
+        peer.bootstrap(&seeds).await?;
+    }
+    let mut hashes = Vec::new();
+    for i in 0..sim.peers.len() {
+        let peer = &sim.peers[sim.rng.gen_range(0..sim.peers.len())];
+        let hash = peer
+            .store(format!("Value {}", i).into_bytes().into_boxed_slice())
+            .await?;
+        hashes.push(hash);
+    }
+    // Let the values reach the peers closest to them, and let the network go quiet
+    // for long enough that unreachable peers can only be reached through their relays.
+    tokio::time::sleep(CONNECTION_TIMEOUT).await;
+
+    let found = find_all(&mut sim, &hashes).await?;
+    let half: Vec<_> = (0..sim.peers.len() / 2).collect();
+    sim.partition(&[&half]);
+    let found_while_partitioned = find_all(&mut sim, &hashes).await?;
+    sim.heal();
+    let found_after_healing = find_all(&mut sim, &hashes).await?;
+    Ok(LookupReport {
+        stored: hashes.len(),
+        found,
+        found_while_partitioned,
+        found_after_healing,
+        stats: sim.stats(),
+    })
+}
+
+/// Look up each of `hashes` from a random peer, and count how many were found.
+async fn find_all(sim: &mut Simulation, hashes: &[Id]) -> Result<usize, Box<dyn Error>> {
+    let mut found = 0;
+    for hash in hashes {
+        let peer = &sim.peers[sim.rng.gen_range(0..sim.peers.len())];
+        if peer.find(hash).await?.is_some() {
+            found += 1;
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_run() {
+        let run = |seed| {
+            let config = Config {
+                peers: 16,
+                seed,
+                loss: 0.05,
+                ..Config::default()
+            };
+            runtime().unwrap().block_on(lookups(&config)).unwrap()
+        };
+        let first = run(7);
+        assert_eq!(first, run(7));
+        assert_ne!(first.stats.digest, run(8).stats.digest);
+    }
+
+    #[test]
+    fn unreachable_peers_need_relays() {
+        // More peers than a value is replicated to, so that values end up on unreachable peers.
+        let run = |relays| {
+            let config = Config {
+                peers: 48,
+                unreachable: 40,
+                relays,
+                ..Config::default()
+            };
+            runtime().unwrap().block_on(lookups(&config)).unwrap()
+        };
+        let with_relays = run(true);
+        assert!(with_relays.found * 10 >= with_relays.stored * 9);
+        let without_relays = run(false);
+        assert!(without_relays.found * 2 < without_relays.stored);
+    }
+
+    #[test]
+    fn partition_with_loss() {
+        let config = Config {
+            peers: 16,
+            seed: 3,
+            loss: 0.02,
+            ..Config::default()
+        };
+        runtime().unwrap().block_on(async {
+            let mut sim = Simulation::new(&config);
+            let half: Vec<_> = (0..8).collect();
+            sim.partition(&[&half]);
+            // Each side bootstraps from a peer of its own, so it can't learn about the other.
+            for side in [0, 8] {
+                let seeds = [sim.peers[side].info()];
+                for peer in &sim.peers[side + 1..side + 8] {
+                    peer.bootstrap(&seeds).await.unwrap();
+                }
+            }
+            let mut hashes = Vec::new();
+            for i in 0..16 {
+                let data = format!("Value {}", i).into_bytes().into_boxed_slice();
+                hashes.push((i / 8, sim.peers[i].store(data).await.unwrap()));
+            }
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            for (side, hash) in &hashes {
+                let same_side = side * 8 + sim.rng.gen_range(0..8);
+                let other_side = (1 - side) * 8 + sim.rng.gen_range(0..8);
+                assert!(sim.peers[same_side].find(hash).await.unwrap().is_some());
+                assert!(sim.peers[other_side].find(hash).await.unwrap().is_none());
+            }
+        });
+    }
+}
@@ -0,0 +1,185 @@
+//! Execution traces, for finding out what a block did and where a run went differently.
+//!
+//! While a node is tracing, every block it runs adds events to the trace: when it
+//! starts, each IO action it takes, and how it ends. Calls to other blocks show up
+//! as a nested start and end. Traces are turned into JSON to be read or kept, and a
+//! node can replay a trace, running the block again and comparing the events.
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{types::Id, value::Value};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Trace {
+    pub events: Vec<Event>,
+}
+
+/// Something that happened while running a block. Hashes are in hex, the way
+/// `crypto.U256.deserialize` takes them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Start {
+        hash: String,
+        name: Option<String>,
+    },
+    Call {
+        hash: String,
+        max_mana: u64,
+        max_memo: u64,
+    },
+    /// The block made a marking function, which marks values with `hash`.
+    Mark {
+        hash: String,
+    },
+    /// The block opened a value, which was marked by `marked_by`, if it was marked.
+    Open {
+        marked_by: Option<String>,
+    },
+    /// The block returned `value`, which is `None` if it isn't plain data.
+    Done {
+        hash: String,
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "json_value")]
+        value: Option<Value>,
+        mana_used: u64,
+        memo_used: u64,
+    },
+    Failed {
+        hash: String,
+        error: String,
+        mana_used: u64,
+        memo_used: u64,
+    },
+}
+
+impl Event {
+    /// Whether the events are the same, other than in how much memory was used.
+    /// That depends on when the garbage collector runs, so it changes between runs.
+    pub fn matches(&self, other: &Event) -> bool {
+        let mut a = self.clone();
+        let mut b = other.clone();
+        for event in [&mut a, &mut b] {
+            if let Event::Done { memo_used, .. } | Event::Failed { memo_used, .. } = event {
+                *memo_used = 0;
+            }
+        }
+        a == b
+    }
+}
+
+/// The first place where two traces differ.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub index: usize,
+    /// `None` if the expected trace ended here.
+    pub expected: Option<Event>,
+    /// `None` if the trace ended here.
+    pub found: Option<Event>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |event: &Option<Event>| match event {
+            Some(event) => serde_json::to_string(event).unwrap(),
+            None => "the end of the trace".to_string(),
+        };
+        write!(
+            f,
+            "Event {}: expected {}, found {}",
+            self.index,
+            show(&self.expected),
+            show(&self.found)
+        )
+    }
+}
+
+impl Trace {
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+    /// Where `found` first differs from this trace, if it does.
+    pub fn divergence(&self, found: &Trace) -> Option<Divergence> {
+        let len = self.events.len().max(found.events.len());
+        (0..len).find_map(|index| {
+            let expected = self.events.get(index);
+            let found = found.events.get(index);
+            match (expected, found) {
+                (Some(a), Some(b)) if a.matches(b) => None,
+                _ => Some(Divergence {
+                    index,
+                    expected: expected.cloned(),
+                    found: found.cloned(),
+                }),
+            }
+        })
+    }
+}
+
+/// Values are written in traces the way `Value::to_json` writes them. A value that
+/// isn't plain data is left out.
+mod json_value {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::value::Value;
+
+    pub fn serialize<S: Serializer>(value: &Option<Value>, s: S) -> Result<S::Ok, S::Error> {
+        value.as_ref().map(Value::to_json).serialize(s)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Value>, D::Error> {
+        let json = serde_json::Value::deserialize(d)?;
+        Value::from_json(&json)
+            .map(Some)
+            .map_err(|e| D::Error::custom(e.to_string()))
+    }
+}
+
+/// How ids are written in traces.
+pub fn id(hash: &Id) -> String {
+    hex::encode(hash.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn done(value: Option<Value>) -> Event {
+        Event::Done {
+            hash: "00".to_string(),
+            value,
+            mana_used: 1,
+            memo_used: 2,
+        }
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let value = Value::Table(vec![
+            (Value::Integer(1), Value::Number(f64::NAN)),
+            (Value::Integer(2), Value::Number(f64::INFINITY)),
+            (Value::Integer(3), Value::Number(f64::NEG_INFINITY)),
+            (Value::Integer(4), Value::Number(0.1)),
+            (Value::Integer(5), Value::Number(1e300)),
+            (Value::Integer(6), Value::Integer(i64::MIN)),
+            (Value::Integer(7), Value::String(b"abc".to_vec())),
+            (Value::Integer(8), Value::String(vec![0xff, 0])),
+            (Value::Integer(9), Value::Bytes(vec![1, 2])),
+            (Value::Integer(10), Value::U256([3; 32])),
+            (Value::Integer(11), Value::Signature(vec![4; 64])),
+            (Value::Boolean(true), Value::Table(Vec::new())),
+        ]);
+        let trace = Trace {
+            events: vec![done(Some(value)), done(Some(Value::Nil)), done(None)],
+        };
+        let json = trace.to_json();
+        assert!(json.contains("\"abc\""), "{}", json);
+        assert!(json.contains("\"ff00\""), "{}", json);
+        assert_eq!(Trace::from_json(&json).unwrap(), trace);
+    }
+}
@@ -0,0 +1,666 @@
+//! Plain data that can leave the Lua VM it was created in.
+//!
+//! Block results usually contain closures, which only make sense in the VM that
+//! ran the block. Results made only of plain data can be converted to a `Value`
+//! and sent to other nodes.
+//!
+//! Values also have a canonical encoding, which is what gets hashed and signed
+//! when a contract works with structured data. Equal values always have the same
+//! encoding, and `Value::decode` rejects anything that isn't the encoding of some
+//! value. Each value starts with a tag byte, followed by its contents. All
+//! integers are little-endian:
+//!
+//! ```text
+//! nil        0
+//! false      1
+//! true       2
+//! integer    3   i64
+//! number     4   f64, only for numbers that aren't integers in the range of an i64
+//! string     5   u32 length, followed by the bytes
+//! table      6   u32 amount of entries, followed by each key and then its value,
+//!                in the order of the encodings of the keys
+//! U256       7   32 bytes, little-endian
+//! scalar     8   32 bytes, less than the group order
+//! point      9   32 bytes, compressed the canonical way
+//! Ristretto  10  32 bytes
+//! bytes      11  u32 length, followed by the bytes
+//! public key 12  32 bytes, compressed the canonical way
+//! signature  13  64 bytes, with `s` less than the group order
+//! ```
+//!
+//! The types in `crypto` are encoded the way their `to_bytes` method encodes them.
+//! Range proofs and signing keys aren't plain data and have no tag.
+//! All NaNs are encoded as the same NaN.
+//!
+//! Values are equal when their encodings are, so unlike numbers, all NaNs are
+//! equal. That way a block that returns NaN gets the same result on every node.
+//!
+//! `crypto.hash_value` hashes this encoding into an id. Some encodings, and their
+//! ids with the default algorithm, Blake2s-256, as `crypto.U256`s:
+//!
+//! ```text
+//! nil                  00
+//!                      0xEA4BD61AE52FC8AAFB57776C844826EAD2510422CC71D8ABC6F44FAFDB744D00
+//! 1                    030100000000000000
+//!                      0x4827B367A459576237CC4D79A2F6C3A09EB68BFF4C929C2A9F8C57CEC4A68F00
+//! 1.5                  04000000000000f83f
+//!                      0x949CF697C1DC2CBAAAFA18C31DA91959DE121155D94A9FBDDAC9EC9280FDA800
+//! "abc"                0503000000616263
+//!                      0x62E093498A6FE07BE29881ED265DEC19E932B546A3318109EC3F6EE6C5373400
+//! {a = 1, b = {true}}  0602000000050100000061030100000000000000050100000062060100000003010000000000000002
+//!                      0x7C2D0955C4292B09C290A47D3314A70BF7727F6B259906B18EB275ABA0A23A00
+//! ```
+use std::error::Error;
+
+use curve25519_dalek::{
+    edwards::CompressedEdwardsY, ristretto::CompressedRistretto, scalar::Scalar,
+};
+use mlua::prelude::*;
+use serde_json::json;
+
+use crate::{
+    block::Reader,
+    lua_curve25519::{
+        Encoding, LuaBytes, LuaEdwardsPoint, LuaPublicKey, LuaRistretto, LuaScalar, LuaSignature,
+        LuaU256,
+    },
+};
+
+/// How deeply tables can be nested inside each other.
+pub const MAX_DEPTH: usize = 256;
+
+/// The longest encoding a value converted from Lua can have. A table can hold the
+/// same subtable under many keys, and each copy counts, so without it a few
+/// nested tables could take exponential time and memory to convert.
+pub const MAX_ENCODING_LEN: usize = 4 << 20;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    /// A number that isn't an `Integer`.
+    Number(f64),
+    String(Vec<u8>),
+    /// Entries are sorted by the encoding of their keys.
+    Table(Vec<(Value, Value)>),
+    U256([u8; 32]),
+    Scalar([u8; 32]),
+    Point([u8; 32]),
+    Ristretto([u8; 32]),
+    Bytes(Vec<u8>),
+    PublicKey([u8; 32]),
+    /// Always 64 bytes.
+    Signature(Vec<u8>),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.encode() == other.encode()
+    }
+}
+
+/// What `Value::from_lua` keeps track of while converting a value.
+struct Conversion<'a> {
+    /// The tables that the part being converted is in.
+    parents: Vec<*const std::ffi::c_void>,
+    /// The length of the encoding of what's been converted so far.
+    len: usize,
+    grow: &'a mut dyn FnMut(usize) -> LuaResult<()>,
+}
+
+impl Conversion<'_> {
+    fn grow(&mut self, len: usize) -> LuaResult<()> {
+        self.len += len;
+        if self.len > MAX_ENCODING_LEN {
+            return Err(format!(
+                "Values can't take more than {} bytes to encode",
+                MAX_ENCODING_LEN
+            )
+            .into_lua_err());
+        }
+        (self.grow)(len)
+    }
+}
+
+impl Value {
+    pub fn from_lua(value: LuaValue) -> LuaResult<Self> {
+        Self::from_lua_counting(value, &mut |_| Ok(()))
+    }
+    /// Like `from_lua`, but calls `grow` with how many bytes the encoding grew by
+    /// each time part of the value is converted, so that the caller can charge for
+    /// it as it goes, and stop it by returning an error.
+    pub fn from_lua_counting(
+        value: LuaValue,
+        grow: &mut dyn FnMut(usize) -> LuaResult<()>,
+    ) -> LuaResult<Self> {
+        Self::from_lua_inner(
+            value,
+            &mut Conversion {
+                parents: Vec::new(),
+                len: 0,
+                grow,
+            },
+        )
+    }
+    fn from_lua_inner(value: LuaValue, conversion: &mut Conversion) -> LuaResult<Self> {
+        let value = match value {
+            LuaValue::Nil => Value::Nil,
+            LuaValue::Boolean(x) => Value::Boolean(x),
+            LuaValue::Integer(x) => Value::Integer(x),
+            LuaValue::Number(x) => Value::number(x),
+            LuaValue::String(x) => Value::String(x.as_bytes().to_vec()),
+            LuaValue::Table(x) => {
+                let ptr = x.to_pointer();
+                let parents = &mut conversion.parents;
+                if parents.contains(&ptr) {
+                    return Err("Can't convert a table that contains itself".into_lua_err());
+                }
+                if parents.len() >= MAX_DEPTH {
+                    return Err("Tables are nested too deeply".into_lua_err());
+                }
+                parents.push(ptr);
+                // The tag and the amount of entries.
+                conversion.grow(5)?;
+                let mut entries = Vec::new();
+                for pair in x.pairs::<LuaValue, LuaValue>() {
+                    let (k, v) = pair?;
+                    let k = Self::from_lua_inner(k, conversion)?;
+                    // Say where the value is, since it can be deep inside the table.
+                    let v = Self::from_lua_inner(v, conversion)
+                        .map_err(|x| format!("In {}: {}", k.describe_key(), x).into_lua_err())?;
+                    entries.push((k, v));
+                }
+                conversion.parents.pop();
+                entries.sort_by_cached_key(|(k, _)| k.encode());
+                return Ok(Value::Table(entries));
+            }
+            LuaValue::UserData(ref x) => {
+                if let Ok(x) = x.borrow::<LuaU256>() {
+                    Value::U256(x.0.to_le_bytes())
+                } else if let Ok(x) = x.borrow::<LuaScalar>() {
+                    Value::Scalar(x.0.to_bytes())
+                } else if let Ok(x) = x.borrow::<LuaEdwardsPoint>() {
+                    Value::Point(x.0.compress().to_bytes())
+                } else if let Ok(x) = x.borrow::<LuaRistretto>() {
+                    Value::Ristretto(x.0.compress().to_bytes())
+                } else if let Ok(x) = x.borrow::<LuaBytes>() {
+                    Value::Bytes(x.0.clone())
+                } else if let Ok(x) = x.borrow::<LuaPublicKey>() {
+                    Value::PublicKey(x.0.to_bytes())
+                } else if let Ok(x) = x.borrow::<LuaSignature>() {
+                    Value::Signature(x.0.to_vec())
+                } else {
+                    return Err(
+                        format!("Can't convert {} to plain data", value.type_name()).into_lua_err()
+                    );
+                }
+            }
+            x => {
+                return Err(format!("Can't convert {} to plain data", x.type_name()).into_lua_err())
+            }
+        };
+        conversion.grow(value.encoded_len())?;
+        Ok(value)
+    }
+    /// The length of the encoding of a value that isn't a table.
+    fn encoded_len(&self) -> usize {
+        match self {
+            Value::Nil | Value::Boolean(_) => 1,
+            Value::Integer(_) | Value::Number(_) => 9,
+            Value::String(x) | Value::Bytes(x) => 5 + x.len(),
+            Value::Signature(_) => 65,
+            Value::Table(_) => unreachable!("tables are counted as they're converted"),
+            _ => 33,
+        }
+    }
+    fn describe_key(&self) -> String {
+        match self {
+            Value::String(x) => format!("{:?}", String::from_utf8_lossy(x)),
+            Value::Integer(x) => format!("[{}]", x),
+            _ => "a table".to_string(),
+        }
+    }
+    /// A number, as an `Integer` if it is one. Lua doesn't tell them apart, so
+    /// neither does the encoding.
+    fn number(x: f64) -> Self {
+        if x.fract() == 0.0 && x >= i64::MIN as f64 && x < i64::MAX as f64 {
+            Value::Integer(x as i64)
+        } else if x.is_nan() {
+            Value::Number(f64::NAN)
+        } else {
+            Value::Number(x)
+        }
+    }
+    pub fn into_lua<'lua>(self, lua: &'lua Lua) -> LuaResult<LuaValue<'lua>> {
+        Ok(match self {
+            Value::Nil => LuaValue::Nil,
+            Value::Boolean(x) => LuaValue::Boolean(x),
+            Value::Integer(x) => LuaValue::Integer(x),
+            Value::Number(x) => LuaValue::Number(x),
+            Value::String(x) => lua.create_string(x)?.into_lua(lua)?,
+            Value::Table(entries) => {
+                let table = lua.create_table()?;
+                for (k, v) in entries {
+                    table.raw_set(k.into_lua(lua)?, v.into_lua(lua)?)?;
+                }
+                table.into_lua(lua)?
+            }
+            Value::U256(x) => LuaU256(ethnum::U256::from_le_bytes(x)).into_lua(lua)?,
+            Value::Scalar(x) => LuaScalar(Scalar::from_bytes_mod_order(x)).into_lua(lua)?,
+            Value::Point(x) => LuaEdwardsPoint(
+                CompressedEdwardsY(x)
+                    .decompress()
+                    .ok_or("Invalid point".into_lua_err())?,
+            )
+            .into_lua(lua)?,
+            Value::Ristretto(x) => LuaRistretto(
+                CompressedRistretto(x)
+                    .decompress()
+                    .ok_or("Invalid Ristretto encoding".into_lua_err())?,
+            )
+            .into_lua(lua)?,
+            Value::Bytes(x) => LuaBytes(x).into_lua(lua)?,
+            Value::PublicKey(x) => LuaPublicKey::from_bytes(&x)?.into_lua(lua)?,
+            Value::Signature(x) => LuaSignature::from_bytes(&x)?.into_lua(lua)?,
+        })
+    }
+    /// The canonical encoding of the value, see the module documentation.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Nil => out.push(0),
+            Value::Boolean(false) => out.push(1),
+            Value::Boolean(true) => out.push(2),
+            Value::Integer(x) => {
+                out.push(3);
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+            Value::Number(x) => match Value::number(*x) {
+                Value::Number(x) => {
+                    out.push(4);
+                    out.extend_from_slice(&x.to_le_bytes());
+                }
+                x => x.encode_into(out),
+            },
+            Value::String(x) => {
+                out.push(5);
+                out.extend_from_slice(&(x.len() as u32).to_le_bytes());
+                out.extend_from_slice(x);
+            }
+            Value::Table(entries) => {
+                out.push(6);
+                out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+                let mut entries: Vec<_> = entries.iter().map(|(k, v)| (k.encode(), v)).collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                for (k, v) in entries {
+                    out.extend_from_slice(&k);
+                    v.encode_into(out);
+                }
+            }
+            Value::U256(x) => {
+                out.push(7);
+                out.extend_from_slice(x);
+            }
+            Value::Scalar(x) => {
+                out.push(8);
+                out.extend_from_slice(x);
+            }
+            Value::Point(x) => {
+                out.push(9);
+                out.extend_from_slice(x);
+            }
+            Value::Ristretto(x) => {
+                out.push(10);
+                out.extend_from_slice(x);
+            }
+            Value::Bytes(x) => {
+                out.push(11);
+                out.extend_from_slice(&(x.len() as u32).to_le_bytes());
+                out.extend_from_slice(x);
+            }
+            Value::PublicKey(x) => {
+                out.push(12);
+                out.extend_from_slice(x);
+            }
+            Value::Signature(x) => {
+                out.push(13);
+                out.extend_from_slice(x);
+            }
+        }
+    }
+    /// The value as JSON, for people to read. Integers, numbers other than NaN and
+    /// the infinities, strings in UTF-8, booleans and nil are written as themselves.
+    /// Everything else is an object with a single field that says what it is, like
+    /// `{"number": "inf"}`, `{"string": "ff00"}` for strings that aren't UTF-8, or
+    /// `{"table": [[key, value], ...]}`. Bytes and the crypto types are in hex, the
+    /// way their `to_bytes` method encodes them.
+    pub fn to_json(&self) -> serde_json::Value {
+        let tagged = |tag: &str, contents: serde_json::Value| json!({ tag: contents });
+        let hex = |tag: &str, bytes: &[u8]| tagged(tag, json!(hex::encode(bytes)));
+        match self {
+            Value::Nil => serde_json::Value::Null,
+            Value::Boolean(x) => json!(x),
+            Value::Integer(x) => json!(x),
+            Value::Number(x) if x.is_finite() => json!(x),
+            Value::Number(x) if x.is_nan() => tagged("number", json!("nan")),
+            Value::Number(x) if *x > 0.0 => tagged("number", json!("inf")),
+            Value::Number(_) => tagged("number", json!("-inf")),
+            Value::String(x) => match std::str::from_utf8(x) {
+                Ok(x) => json!(x),
+                Err(_) => hex("string", x),
+            },
+            Value::Table(entries) => tagged(
+                "table",
+                entries
+                    .iter()
+                    .map(|(k, v)| json!([k.to_json(), v.to_json()]))
+                    .collect(),
+            ),
+            Value::U256(x) => hex("u256", x),
+            Value::Scalar(x) => hex("scalar", x),
+            Value::Point(x) => hex("point", x),
+            Value::Ristretto(x) => hex("ristretto", x),
+            Value::Bytes(x) => hex("bytes", x),
+            Value::PublicKey(x) => hex("public_key", x),
+            Value::Signature(x) => hex("signature", x),
+        }
+    }
+    /// The value that `to_json` turns into `json`.
+    pub fn from_json(json: &serde_json::Value) -> Result<Value, Box<dyn Error>> {
+        let object = match json {
+            serde_json::Value::Null => return Ok(Value::Nil),
+            serde_json::Value::Bool(x) => return Ok(Value::Boolean(*x)),
+            serde_json::Value::Number(x) => {
+                return Ok(match x.as_i64() {
+                    Some(x) => Value::Integer(x),
+                    None => Value::number(x.as_f64().ok_or("Invalid number")?),
+                })
+            }
+            serde_json::Value::String(x) => return Ok(Value::String(x.as_bytes().to_vec())),
+            serde_json::Value::Object(object) if object.len() == 1 => object,
+            x => return Err(format!("Invalid value {}", x).into()),
+        };
+        let (tag, contents) = object.iter().next().unwrap();
+        let hex = || -> Result<Vec<u8>, Box<dyn Error>> {
+            Ok(hex::decode(contents.as_str().ok_or("Expected hex")?)?)
+        };
+        let array = || -> Result<[u8; 32], Box<dyn Error>> {
+            Ok(hex()?.try_into().map_err(|_| "Expected 32 bytes")?)
+        };
+        Ok(match tag.as_str() {
+            "number" => Value::Number(match contents.as_str() {
+                Some("nan") => f64::NAN,
+                Some("inf") => f64::INFINITY,
+                Some("-inf") => f64::NEG_INFINITY,
+                _ => return Err(format!("Invalid number {}", contents).into()),
+            }),
+            "string" => Value::String(hex()?),
+            "table" => {
+                let mut entries = Vec::new();
+                for entry in contents.as_array().ok_or("Expected a list of entries")? {
+                    match entry.as_array().map(|x| x.as_slice()) {
+                        Some([k, v]) => entries.push((Value::from_json(k)?, Value::from_json(v)?)),
+                        _ => return Err("Expected a key and a value".into()),
+                    }
+                }
+                entries.sort_by_cached_key(|(k, _)| k.encode());
+                Value::Table(entries)
+            }
+            "u256" => Value::U256(array()?),
+            "scalar" => Value::Scalar(array()?),
+            "point" => Value::Point(array()?),
+            "ristretto" => Value::Ristretto(array()?),
+            "bytes" => Value::Bytes(hex()?),
+            "public_key" => Value::PublicKey(array()?),
+            "signature" => match hex()? {
+                x if x.len() == 64 => Value::Signature(x),
+                _ => return Err("Expected 64 bytes".into()),
+            },
+            x => return Err(format!("Unknown type of value {:?}", x).into()),
+        })
+    }
+    pub fn decode(data: &[u8]) -> Result<Value, Box<dyn Error>> {
+        let mut reader = Reader(data);
+        let value = Self::decode_inner(&mut reader, 0)?;
+        if !reader.0.is_empty() {
+            return Err("Trailing bytes after value".into());
+        }
+        Ok(value)
+    }
+    fn decode_inner(reader: &mut Reader, depth: usize) -> Result<Value, Box<dyn Error>> {
+        let array = |reader: &mut Reader| -> Result<[u8; 32], Box<dyn Error>> {
+            Ok(reader.bytes(32)?.try_into()?)
+        };
+        Ok(match reader.u8()? {
+            0 => Value::Nil,
+            1 => Value::Boolean(false),
+            2 => Value::Boolean(true),
+            3 => Value::Integer(i64::from_le_bytes(reader.bytes(8)?.try_into()?)),
+            4 => {
+                let x = f64::from_le_bytes(reader.bytes(8)?.try_into()?);
+                match Value::number(x) {
+                    Value::Number(y) if y.to_bits() == x.to_bits() => Value::Number(x),
+                    _ => return Err("Number isn't encoded the canonical way".into()),
+                }
+            }
+            5 => {
+                let len = reader.u32()?;
+                Value::String(reader.bytes(len as usize)?.to_vec())
+            }
+            6 => {
+                if depth >= MAX_DEPTH {
+                    return Err("Tables are nested too deeply".into());
+                }
+                let amount = reader.u32()?;
+                let mut entries = Vec::new();
+                let mut previous: Option<&[u8]> = None;
+                for _ in 0..amount {
+                    let start = reader.0;
+                    let k = Self::decode_inner(reader, depth + 1)?;
+                    let encoding = &start[..start.len() - reader.0.len()];
+                    if previous.is_some_and(|x| x >= encoding) {
+                        return Err("Table keys are out of order".into());
+                    }
+                    previous = Some(encoding);
+                    let v = Self::decode_inner(reader, depth + 1)?;
+                    if matches!(k, Value::Number(x) if x.is_nan()) || k == Value::Nil {
+                        return Err("Invalid table key".into());
+                    }
+                    if v == Value::Nil {
+                        return Err("Tables can't contain nil".into());
+                    }
+                    entries.push((k, v));
+                }
+                Value::Table(entries)
+            }
+            7 => Value::U256(array(reader)?),
+            // The crypto types check that their encodings are canonical.
+            8 => {
+                let x = array(reader)?;
+                LuaScalar::from_bytes(&x).map_err(|x| x.to_string())?;
+                Value::Scalar(x)
+            }
+            9 => {
+                let x = array(reader)?;
+                LuaEdwardsPoint::from_bytes(&x).map_err(|x| x.to_string())?;
+                Value::Point(x)
+            }
+            10 => {
+                let x = array(reader)?;
+                LuaRistretto::from_bytes(&x).map_err(|x| x.to_string())?;
+                Value::Ristretto(x)
+            }
+            11 => {
+                let len = reader.u32()?;
+                Value::Bytes(reader.bytes(len as usize)?.to_vec())
+            }
+            12 => {
+                let x = array(reader)?;
+                LuaPublicKey::from_bytes(&x).map_err(|x| x.to_string())?;
+                Value::PublicKey(x)
+            }
+            13 => {
+                let x = reader.bytes(64)?.to_vec();
+                LuaSignature::from_bytes(&x).map_err(|x| x.to_string())?;
+                Value::Signature(x)
+            }
+            x => return Err(format!("Unknown value tag {}", x).into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script_vm::{new_lua, out_of_mana, Limits};
+
+    /// Evaluate `code` with `crypto` in scope.
+    fn eval<'lua>(lua: &'lua Lua, code: &str) -> LuaResult<LuaValue<'lua>> {
+        lua.load(format!("local crypto = require('crypto')\n{}", code))
+            .eval()
+    }
+
+    /// The vectors in the module documentation.
+    #[test]
+    fn vectors() {
+        let vectors = [
+            (
+                "nil",
+                "00",
+                "0xEA4BD61AE52FC8AAFB57776C844826EAD2510422CC71D8ABC6F44FAFDB744D00",
+            ),
+            (
+                "1",
+                "030100000000000000",
+                "0x4827B367A459576237CC4D79A2F6C3A09EB68BFF4C929C2A9F8C57CEC4A68F00",
+            ),
+            (
+                "1.5",
+                "04000000000000f83f",
+                "0x949CF697C1DC2CBAAAFA18C31DA91959DE121155D94A9FBDDAC9EC9280FDA800",
+            ),
+            (
+                "'abc'",
+                "0503000000616263",
+                "0x62E093498A6FE07BE29881ED265DEC19E932B546A3318109EC3F6EE6C5373400",
+            ),
+            (
+                "{a = 1, b = {true}}",
+                "0602000000050100000061030100000000000000050100000062060100000003010000000000000002",
+                "0x7C2D0955C4292B09C290A47D3314A70BF7727F6B259906B18EB275ABA0A23A00",
+            ),
+        ];
+        let lua = new_lua().unwrap();
+        for (code, encoding, id) in vectors {
+            let value = Value::from_lua(eval(&lua, &format!("return {}", code)).unwrap()).unwrap();
+            assert_eq!(
+                hex::encode(value.encode()),
+                encoding,
+                "encoding of {}",
+                code
+            );
+            assert_eq!(
+                Value::decode(&hex::decode(encoding).unwrap()).unwrap(),
+                value
+            );
+            let same_id = eval(
+                &lua,
+                &format!(
+                    "return crypto.hash_value({}) == crypto.U256.from_string('{}')",
+                    code, id
+                ),
+            )
+            .unwrap();
+            assert_eq!(same_id, LuaValue::Boolean(true), "id of {}", code);
+        }
+    }
+
+    #[test]
+    fn unhashable_values() {
+        let lua = new_lua().unwrap();
+        let nested = |depth: usize| {
+            format!(
+                "local t = {{}}
+                for i = 2, {} do t = {{t}} end
+                return crypto.hash_value(t)",
+                depth
+            )
+        };
+        for code in [
+            "return crypto.hash_value(function() end)".to_string(),
+            "return crypto.hash_value({a = {print}})".to_string(),
+            "local t = {} t.t = {t} return crypto.hash_value(t)".to_string(),
+            nested(MAX_DEPTH + 1),
+        ] {
+            assert!(eval(&lua, &code).is_err(), "{} didn't fail", code);
+        }
+        eval(&lua, &nested(MAX_DEPTH)).unwrap();
+    }
+
+    #[test]
+    fn shared_subtables() {
+        let lua = new_lua().unwrap();
+        let shared = |depth: usize| {
+            format!(
+                "local t = {{}}
+                for i = 1, {} do t = {{t, t}} end
+                return t",
+                depth
+            )
+        };
+        // Each copy is part of the value: 7 tables with two entries, and 8 empty ones.
+        let value = Value::from_lua(eval(&lua, &shared(3)).unwrap()).unwrap();
+        assert_eq!(value.encode().len(), 7 * (5 + 2 * 9) + 8 * 5);
+        // 2^40 copies are too many, and that's found out long before converting them.
+        let start = std::time::Instant::now();
+        let t = eval(&lua, &shared(40)).unwrap();
+        assert!(Value::from_lua(t.clone()).is_err());
+        let hash_value =
+            LuaFunction::from_lua(eval(&lua, "return crypto.hash_value").unwrap(), &lua).unwrap();
+        assert!(hash_value.call::<_, LuaValue>(t.clone()).is_err());
+        // With a mana limit, it runs out of mana first.
+        {
+            let _limits = Limits::enter(&lua, 100_000, 0);
+            assert!(hash_value.call::<_, LuaValue>(t).is_err());
+            assert!(out_of_mana());
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    /// `lua/crypto_util.lua` signs the encoding of a value, and CatCoin checks those
+    /// signatures, so the ids they get must not change now that `lua/hash.lua` is gone.
+    #[test]
+    fn crypto_util_ids() {
+        let lua = new_lua().unwrap();
+        let checks = eval(
+            &lua,
+            "local crypto_util = require('lua/crypto_util')
+            -- The kind of transaction that CatCoin accounts check the signature of.
+            local transaction = {
+                amount = 16,
+                parent_hash = crypto.U256.from(1),
+                dest = crypto.U256.from(2),
+            }
+            local bytes = crypto_util.message_bytes(transaction)
+            local sk = crypto_util.set_sk_metatable({
+                key = crypto.SigningKey.from_bytes(crypto.Bytes.from(string.rep('k', 32))),
+            })
+            return {
+                bytes == crypto.encode(transaction),
+                crypto.U256.hash(bytes) == crypto.hash_value(transaction),
+                crypto.hash_value(transaction) == crypto.U256.from_string(
+                    '0x627828B420F1E1E81237659B842EA6181B01F133C39E95E74149FFB933277100'),
+                sk:pk():verify(sk:sign(transaction), transaction),
+                not pcall(require, 'lua/hash'),
+            }",
+        )
+        .unwrap();
+        let checks = Vec::<bool>::from_lua(checks, &lua).unwrap();
+        assert_eq!(checks, vec![true; 5]);
+    }
+}